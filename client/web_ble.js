@@ -205,20 +205,29 @@ async function readWiFiScannerResults() {
     }
     await connectDeviceAndCacheCharacteristics();
 
-	result_all = "";
     log('> Reading Wi-Fi Scanner Results...');
 	value = await wifiScannerAP_Select_Characteristic.readValue();
 	max_records = value.getUint8(0);
 	console.log(`Number of result records: ${max_records}`);
-	var enc = new TextDecoder("utf-8");
+	// a UTF-8 character may be split across records, so decode them as a whole
+	var parts = [];
+	var length = 0;
 	for (let i = 0; i < max_records; i++) {
 		const select_value = Uint8Array.of(i);
 		await wifiScannerAP_Select_Characteristic.writeValue(select_value)
 		result_part = await wifiScannerAP_Result_Characteristic.readValue();
-		str = enc.decode(new Uint8Array(result_part.buffer));
-		console.log(`Result part ${str}`);
-		result_all += str;
+		const part = new Uint8Array(result_part.buffer, result_part.byteOffset, result_part.byteLength);
+		console.log(`Result part ${i} with ${part.length} bytes`);
+		parts.push(part);
+		length += part.length;
 	}
+	var result_bytes = new Uint8Array(length);
+	var offset = 0;
+	for (const part of parts) {
+		result_bytes.set(part, offset);
+		offset += part.length;
+	}
+	result_all = new TextDecoder("utf-8").decode(result_bytes);
 
 	log('> Results: ' + result_all);
       
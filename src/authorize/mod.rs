@@ -1,7 +1,8 @@
+use crate::request::Request;
 use async_trait::async_trait;
 use bluer::gatt::local::{
     characteristic_control, service_control, Characteristic, CharacteristicWrite,
-    CharacteristicWriteMethod, CharacteristicWriteRequest, ReqError, ReqResult, Service,
+    CharacteristicWriteMethod, ReqError, ReqResult, Service,
 };
 use enclose::enclose;
 use futures::FutureExt;
@@ -40,11 +41,11 @@ impl AuthorizeSharedData {
 async fn write_key(
     shared: Arc<AuthorizeSharedData>,
     new_value: Vec<u8>,
-    req: CharacteristicWriteRequest,
+    req: impl Request,
 ) -> ReqResult<()> {
     info!("Key write request {:?}", &req);
     debug!(" value {:x?}", &new_value);
-    let offset = req.offset as usize;
+    let offset = req.offset() as usize;
     let len = new_value.len();
     if len + offset > sha3::Sha3_256::output_size() {
         error!("Key write invalid length.");
@@ -124,3 +125,14 @@ impl Authorized for AuthorizeService {
         return !authorized_timeout.is_zero();
     }
 }
+
+#[cfg(test)]
+pub struct MockAuthorized(pub bool);
+
+#[cfg(test)]
+#[async_trait]
+impl Authorized for MockAuthorized {
+    async fn is_authorized(&self) -> bool {
+        self.0
+    }
+}
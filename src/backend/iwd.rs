@@ -0,0 +1,414 @@
+// The BLE client only transfers the PBKDF2 derived PSK, whereas iwd's agent
+// interface only accepts passphrases. The network is therefore provisioned as
+// a known network with a PreSharedKey in iwd's storage directory before
+// Network.Connect is called, so iwd never has to ask an agent for it.
+
+use super::{write_private, AccessPoint, LinkStats, Status, WifiBackend};
+use async_trait::async_trait;
+use dbus::arg::{prop_cast, PropMap};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::{ObjectManager, Properties};
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::Path;
+use log::{error, info, warn};
+use std::path::Path as FsPath;
+use std::sync::Arc;
+use std::time::Duration;
+
+const IWD_BUS: &str = "net.connman.iwd";
+const IWD_DEVICE_IFACE: &str = "net.connman.iwd.Device";
+const IWD_STATION_IFACE: &str = "net.connman.iwd.Station";
+const IWD_NETWORK_IFACE: &str = "net.connman.iwd.Network";
+const IWD_KNOWN_NETWORK_IFACE: &str = "net.connman.iwd.KnownNetwork";
+const IWD_BSS_IFACE: &str = "net.connman.iwd.BasicServiceSet";
+const IWD_STATION_DIAGNOSTIC_IFACE: &str = "net.connman.iwd.StationDiagnostic";
+const IWD_STORAGE_DIR: &str = "/var/lib/iwd";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+const PROVISION_TIMEOUT: Duration = Duration::from_secs(3);
+// group marking the provisioning files written by this service, iwd ignores
+// and keeps unknown groups
+const PROVISIONED_GROUP: &str = "[WifiCommissioningGatt]";
+
+// Name of the iwd provisioning file for a PSK network, see iwd.network(5).
+fn storage_name(ssid: &str) -> String {
+    if ssid
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == ' ')
+    {
+        format!("{}.psk", ssid)
+    } else {
+        let mut name = String::from("=");
+        for byte in ssid.as_bytes() {
+            name += &format!("{:02x}", byte);
+        }
+        name + ".psk"
+    }
+}
+
+fn provisioning_file(psk_hex: &str) -> String {
+    format!(
+        "[Security]\nPreSharedKey={}\n\n{}\nProvisioned=true\n",
+        psk_hex, PROVISIONED_GROUP
+    )
+}
+
+// Whether the provisioning file was written by this service, the others are
+// configured by the operator and must be left alone.
+fn is_provisioned(content: &str) -> bool {
+    content.lines().any(|line| line.trim() == PROVISIONED_GROUP)
+}
+
+// Translates the iwd network type to wpa_supplicant notation. iwd doesn't
+// tell the WPA version, key management (PSK or SAE) or ciphers, so the
+// flags are unspecific.
+fn wpa_flags(network_type: &str) -> String {
+    match network_type {
+        "wep" => "[WEP][ESS]",
+        "psk" => "[WPA-PSK][ESS]",
+        "8021x" => "[WPA-EAP][ESS]",
+        _ => "[ESS]",
+    }
+    .to_string()
+}
+
+async fn ipv4_address(interface: &str) -> Result<Option<String>, String> {
+    let output = tokio::process::Command::new("ip")
+        .args(["-o", "-4", "addr", "show", "dev", interface])
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    Ok(output
+        .split_whitespace()
+        .skip_while(|word| *word != "inet")
+        .nth(1)
+        .and_then(|cidr| cidr.split('/').next())
+        .map(|ip| ip.to_string()))
+}
+
+pub struct Iwd {
+    connection: Arc<SyncConnection>,
+    interface: String,
+}
+
+impl Iwd {
+    pub fn new(interface: String) -> Result<Iwd, dbus::Error> {
+        let (resource, connection) = dbus_tokio::connection::new_system_sync()?;
+        tokio::spawn(async move {
+            let err = resource.await;
+            error!("Lost connection to D-Bus: {}", err);
+        });
+        Ok(Iwd {
+            connection,
+            interface,
+        })
+    }
+
+    fn proxy<'a>(&self, path: Path<'a>) -> Proxy<'a, Arc<SyncConnection>> {
+        Proxy::new(IWD_BUS, path, DBUS_TIMEOUT, self.connection.clone())
+    }
+
+    // Returns the station object path of the interface and, if given, the
+    // network object path with this name as seen by that station.
+    async fn lookup(
+        &self,
+        ssid: Option<&str>,
+    ) -> Result<(Path<'static>, Option<Path<'static>>), String> {
+        let objects = self
+            .proxy("/".into())
+            .get_managed_objects()
+            .await
+            .map_err(|e| e.to_string())?;
+        let station = objects
+            .iter()
+            .find(|(_, interfaces)| {
+                interfaces.contains_key(IWD_STATION_IFACE)
+                    && interfaces
+                        .get(IWD_DEVICE_IFACE)
+                        .and_then(|device| prop_cast::<String>(device, "Name"))
+                        .is_some_and(|name| *name == self.interface)
+            })
+            .map(|(path, _)| path.clone())
+            .ok_or(format!("No iwd station for {}", self.interface))?;
+        let network = ssid.and_then(|ssid| {
+            objects
+                .iter()
+                .find(|(_, interfaces)| {
+                    interfaces.get(IWD_NETWORK_IFACE).is_some_and(|network| {
+                        prop_cast::<Path>(network, "Device").is_some_and(|d| *d == station)
+                            && prop_cast::<String>(network, "Name").is_some_and(|n| n == ssid)
+                    })
+                })
+                .map(|(path, _)| path.clone())
+        });
+        Ok((station, network))
+    }
+
+    async fn bssid(&self, network: &Proxy<'_, Arc<SyncConnection>>) -> String {
+        // ExtendedServiceSet is only available with iwd >= 2.0
+        let bss: Vec<Path<'static>> =
+            match network.get(IWD_NETWORK_IFACE, "ExtendedServiceSet").await {
+                Ok(bss) => bss,
+                Err(_) => return String::new(),
+            };
+        match bss.into_iter().next() {
+            Some(path) => self
+                .proxy(path)
+                .get(IWD_BSS_IFACE, "Address")
+                .await
+                .unwrap_or_default(),
+            None => String::new(),
+        }
+    }
+
+    // Forgets a network formerly provisioned by this service, fails for
+    // known networks configured otherwise.
+    async fn forget(
+        &self,
+        network: &Proxy<'_, Arc<SyncConnection>>,
+        path: &FsPath,
+    ) -> Result<(), String> {
+        let known: Path<'static> = match network.get(IWD_NETWORK_IFACE, "KnownNetwork").await {
+            Ok(known) => known,
+            // property is absent for unknown networks
+            Err(_) => return Ok(()),
+        };
+        let provisioned =
+            std::fs::read_to_string(path).is_ok_and(|content| is_provisioned(&content));
+        if !provisioned {
+            return Err(format!(
+                "Known network {} was not provisioned by this service, not replacing it.",
+                known
+            ));
+        }
+        info!("Forgetting known network {}", known);
+        self.proxy(known)
+            .method_call::<(), _, _, _>(IWD_KNOWN_NETWORK_IFACE, "Forget", ())
+            .await
+            .map_err(|e| e.to_string())
+    }
+}
+
+#[async_trait]
+impl WifiBackend for Iwd {
+    async fn scan(&self) -> Result<Vec<AccessPoint>, String> {
+        let (station_path, _) = self.lookup(None).await?;
+        let station = self.proxy(station_path);
+        info!("Starting SSID scan");
+        if let Err(e) = station
+            .method_call::<(), _, _, _>(IWD_STATION_IFACE, "Scan", ())
+            .await
+        {
+            // most likely a scan is already in progress
+            warn!("Scan failed: {}", e);
+        }
+        let scan = async {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let scanning: bool = station
+                    .get(IWD_STATION_IFACE, "Scanning")
+                    .await
+                    .map_err(|e| e.to_string())?;
+                if !scanning {
+                    return Ok::<(), String>(());
+                }
+            }
+        };
+        tokio::time::timeout(SCAN_TIMEOUT, scan)
+            .await
+            .map_err(|_| "Scan timed out.".to_string())??;
+        let (networks,): (Vec<(Path<'static>, i16)>,) = station
+            .method_call(IWD_STATION_IFACE, "GetOrderedNetworks", ())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut aps = vec![];
+        for (path, signal) in networks {
+            let network = self.proxy(path);
+            let name: String = network
+                .get(IWD_NETWORK_IFACE, "Name")
+                .await
+                .map_err(|e| e.to_string())?;
+            let network_type: String = network
+                .get(IWD_NETWORK_IFACE, "Type")
+                .await
+                .map_err(|e| e.to_string())?;
+            aps.push(AccessPoint {
+                bssid: self.bssid(&network).await,
+                // iwd does not expose the frequency of a network, so it is unknown
+                frequency: 0,
+                // signal strength is reported in 100 * dBm
+                signal: signal as i32 / 100,
+                flags: wpa_flags(&network_type),
+                ssid: name.into_bytes(),
+            });
+        }
+        info!("Finished SSID scan");
+        Ok(aps)
+    }
+
+    async fn connect(&self, ssid: Vec<u8>, psk: Vec<u8>) -> Result<(), String> {
+        // iwd identifies networks by name and ignores SSIDs that aren't valid UTF-8
+        let ssid_utf8 = String::from_utf8(ssid)
+            .map_err(|e| format!("iwd doesn't support non UTF-8 SSIDs: {}", e))?;
+        let (station, network_path) = self.lookup(Some(&ssid_utf8)).await?;
+        let network_path =
+            network_path.ok_or(format!("Network {} not found, scan first.", &ssid_utf8))?;
+        let network = self.proxy(network_path);
+
+        if let Err(e) = self
+            .proxy(station)
+            .method_call::<(), _, _, _>(IWD_STATION_IFACE, "Disconnect", ())
+            .await
+        {
+            warn!(
+                "Disconnect failed, but this is ok if not connected before: {}",
+                e
+            );
+        }
+        let path = FsPath::new(IWD_STORAGE_DIR).join(storage_name(&ssid_utf8));
+        self.forget(&network, &path).await?;
+
+        let mut psk_hex: String = String::new();
+        for byte in psk {
+            psk_hex = psk_hex + &format!("{:02x}", byte);
+        }
+        write_private(&path, &provisioning_file(&psk_hex))?;
+
+        // iwd picks up the provisioning file asynchronously
+        let provisioned = async {
+            while network
+                .get::<Path<'static>>(IWD_NETWORK_IFACE, "KnownNetwork")
+                .await
+                .is_err()
+            {
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        };
+        tokio::time::timeout(PROVISION_TIMEOUT, provisioned)
+            .await
+            .map_err(|_| format!("iwd did not load {}", path.display()))?;
+
+        // Connect only returns after the connection attempt finished, so don't
+        // block the GATT request on it.
+        let connection = self.connection.clone();
+        let network_path = network.path.clone().into_static();
+        tokio::spawn(async move {
+            let network = Proxy::new(IWD_BUS, network_path, DBUS_TIMEOUT, connection);
+            if let Err(e) = network
+                .method_call::<(), _, _, _>(IWD_NETWORK_IFACE, "Connect", ())
+                .await
+            {
+                error!("Connect failed: {}", e);
+            }
+        });
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        let (station, _) = self.lookup(None).await?;
+        self.proxy(station)
+            .method_call::<(), _, _, _>(IWD_STATION_IFACE, "Disconnect", ())
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn status(&self) -> Result<Status, String> {
+        let (station_path, _) = self.lookup(None).await?;
+        let station = self.proxy(station_path);
+        let state: String = station
+            .get(IWD_STATION_IFACE, "State")
+            .await
+            .map_err(|e| e.to_string())?;
+        let mac_address: String = station
+            .get(IWD_DEVICE_IFACE, "Address")
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut status = Status {
+            completed: state == "connected",
+            mac_address: Some(mac_address),
+            ..Default::default()
+        };
+        if status.completed {
+            status.ip_address = ipv4_address(&self.interface).await?;
+            if let Ok(network) = station
+                .get::<Path<'static>>(IWD_STATION_IFACE, "ConnectedNetwork")
+                .await
+            {
+                status.ssid = self
+                    .proxy(network)
+                    .get::<String>(IWD_NETWORK_IFACE, "Name")
+                    .await
+                    .ok()
+                    .map(String::into_bytes);
+            }
+            // the diagnostic interface is optional, so don't fail without it
+            match station
+                .method_call::<(PropMap,), _, _, _>(
+                    IWD_STATION_DIAGNOSTIC_IFACE,
+                    "GetDiagnostics",
+                    (),
+                )
+                .await
+            {
+                Ok((diagnostics,)) => {
+                    status.bssid = prop_cast::<String>(&diagnostics, "ConnectedBss").cloned();
+                    status.frequency = prop_cast::<u32>(&diagnostics, "Frequency").cloned();
+                    status.signal = prop_cast::<i16>(&diagnostics, "RSSI").map(|rssi| *rssi as i32);
+                }
+                Err(e) => warn!("GetDiagnostics failed: {}", e),
+            }
+        }
+        Ok(status)
+    }
+
+    async fn known_networks(&self) -> Result<Vec<Vec<u8>>, String> {
+        let objects = self
+            .proxy("/".into())
+            .get_managed_objects()
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(objects
+            .values()
+            .filter_map(|interfaces| interfaces.get(IWD_KNOWN_NETWORK_IFACE))
+            .filter_map(|known| prop_cast::<String>(known, "Name"))
+            .map(|name| name.as_bytes().to_vec())
+            .collect())
+    }
+
+    async fn link_stats(&self) -> Result<LinkStats, String> {
+        let (station, _) = self.lookup(None).await?;
+        let (diagnostics,): (PropMap,) = self
+            .proxy(station)
+            .method_call(IWD_STATION_DIAGNOSTIC_IFACE, "GetDiagnostics", ())
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(LinkStats {
+            signal: prop_cast::<i16>(&diagnostics, "RSSI").map(|rssi| *rssi as i32),
+            // bitrate in 100 kbit/s
+            link_speed: prop_cast::<u32>(&diagnostics, "RxBitrate").map(|rate| *rate / 10),
+            // iwd doesn't expose the noise level
+            noise: None,
+            frequency: prop_cast::<u32>(&diagnostics, "Frequency").cloned(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_name() {
+        assert_eq!(storage_name("My Network_1-a"), "My Network_1-a.psk");
+        assert_eq!(storage_name("caf\u{e9}"), "=636166c3a9.psk");
+    }
+
+    #[test]
+    fn test_provisioning_file() {
+        let content = provisioning_file("0123");
+        assert!(content.starts_with("[Security]\nPreSharedKey=0123\n"));
+        assert!(is_provisioned(&content));
+        assert!(!is_provisioned("[Security]\nPassphrase=secret\n"));
+    }
+}
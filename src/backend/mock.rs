@@ -0,0 +1,97 @@
+use super::{AccessPoint, LinkStats, Status, WifiBackend};
+use async_trait::async_trait;
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Scripted replies of the mock backend.
+pub struct Script {
+    pub scan: Result<Vec<AccessPoint>, String>,
+    pub scan_duration: Duration,
+    pub connect: Result<(), String>,
+    pub disconnect: Result<(), String>,
+    // Replies to subsequent status requests, the last one is repeated. This
+    // allows e.g. to simulate a delayed DHCP lease or an authentication
+    // failure after some time.
+    pub status: VecDeque<Result<Status, String>>,
+    pub link_stats: Result<LinkStats, String>,
+    pub known_networks: Result<Vec<Vec<u8>>, String>,
+}
+
+impl Default for Script {
+    fn default() -> Script {
+        Script {
+            scan: Ok(vec![]),
+            scan_duration: Duration::ZERO,
+            connect: Ok(()),
+            disconnect: Ok(()),
+            status: VecDeque::from([Ok(Status::default())]),
+            link_stats: Ok(LinkStats::default()),
+            known_networks: Ok(vec![]),
+        }
+    }
+}
+
+/// Requests the mock backend received.
+#[derive(Debug, Default, PartialEq)]
+pub struct Calls {
+    pub scans: u32,
+    pub connects: Vec<(Vec<u8>, Vec<u8>)>,
+    pub disconnects: u32,
+}
+
+/// In-memory backend for testing the GATT services without wifi hardware.
+#[derive(Default)]
+pub struct Mock {
+    pub script: Mutex<Script>,
+    pub calls: Mutex<Calls>,
+}
+
+impl Mock {
+    pub fn new(script: Script) -> Mock {
+        Mock {
+            script: Mutex::new(script),
+            calls: Mutex::new(Calls::default()),
+        }
+    }
+}
+
+#[async_trait]
+impl WifiBackend for Mock {
+    async fn scan(&self) -> Result<Vec<AccessPoint>, String> {
+        self.calls.lock().unwrap().scans += 1;
+        let (duration, result) = {
+            let script = self.script.lock().unwrap();
+            (script.scan_duration, script.scan.clone())
+        };
+        tokio::time::sleep(duration).await;
+        result
+    }
+
+    async fn connect(&self, ssid: Vec<u8>, psk: Vec<u8>) -> Result<(), String> {
+        self.calls.lock().unwrap().connects.push((ssid, psk));
+        self.script.lock().unwrap().connect.clone()
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        self.calls.lock().unwrap().disconnects += 1;
+        self.script.lock().unwrap().disconnect.clone()
+    }
+
+    async fn status(&self) -> Result<Status, String> {
+        let mut script = self.script.lock().unwrap();
+        if script.status.len() > 1 {
+            script.status.pop_front().unwrap()
+        } else {
+            script.status[0].clone()
+        }
+    }
+
+    async fn known_networks(&self) -> Result<Vec<Vec<u8>>, String> {
+        self.script.lock().unwrap().known_networks.clone()
+    }
+
+    async fn link_stats(&self) -> Result<LinkStats, String> {
+        self.script.lock().unwrap().link_stats.clone()
+    }
+}
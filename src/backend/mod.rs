@@ -0,0 +1,98 @@
+use async_trait::async_trait;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+pub mod iwd;
+#[cfg(test)]
+pub mod mock;
+pub mod network_manager;
+pub mod wpa_supplicant;
+
+/// Access point found by a scan.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AccessPoint {
+    pub bssid: String,
+    // frequency in MHz, 0 if unknown
+    pub frequency: u32,
+    // signal level in dBm
+    pub signal: i32,
+    // capability flags in wpa_supplicant notation, e.g. "[WPA2-PSK-CCMP][ESS]"
+    pub flags: String,
+    // raw SSID, not necessarily valid UTF-8
+    pub ssid: Vec<u8>,
+}
+
+/// Connection status of the wireless interface.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Status {
+    // association and authentication with the AP completed
+    pub completed: bool,
+    pub ip_address: Option<String>,
+    // MAC address of the wireless interface
+    pub mac_address: Option<String>,
+    // the following describe the AP while completed
+    pub bssid: Option<String>,
+    // raw SSID
+    pub ssid: Option<Vec<u8>>,
+    // frequency in MHz
+    pub frequency: Option<u32>,
+    // signal level in dBm
+    pub signal: Option<i32>,
+}
+
+/// Statistics of the current link, as far as the daemon knows them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LinkStats {
+    // signal level in dBm
+    pub signal: Option<i32>,
+    // link speed in Mbit/s
+    pub link_speed: Option<u32>,
+    // noise level in dBm
+    pub noise: Option<i32>,
+    // frequency in MHz
+    pub frequency: Option<u32>,
+}
+
+/// How the network written by the client is persisted.
+#[derive(Clone, Debug)]
+pub enum Persistence {
+    // only configure the running daemon
+    Memory,
+    // write the network to a separate configuration file owned by this service
+    DropIn(PathBuf),
+    // let the daemon rewrite its own configuration
+    SaveConfig,
+}
+
+// Replaces the file atomically with content holding credentials, which must
+// not be readable by others.
+pub(crate) fn write_private(path: &Path, content: &str) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| format!("Creating {} failed: {}", tmp_path.display(), e))?;
+    file.write_all(content.as_bytes())
+        .and_then(|_| file.sync_all())
+        .map_err(|e| format!("Writing {} failed: {}", tmp_path.display(), e))?;
+    std::fs::rename(&tmp_path, path)
+        .map_err(|e| format!("Renaming {} failed: {}", tmp_path.display(), e))
+}
+
+/// Operations the GATT services need from the system's wifi management daemon.
+#[async_trait]
+pub trait WifiBackend {
+    async fn scan(&self) -> Result<Vec<AccessPoint>, String>;
+    async fn connect(&self, ssid: Vec<u8>, psk: Vec<u8>) -> Result<(), String>;
+    async fn disconnect(&self) -> Result<(), String>;
+    async fn status(&self) -> Result<Status, String>;
+    // SSIDs of the networks the daemon has credentials for
+    async fn known_networks(&self) -> Result<Vec<Vec<u8>>, String>;
+    async fn link_stats(&self) -> Result<LinkStats, String> {
+        Err("link statistics are not supported by this backend".to_string())
+    }
+}
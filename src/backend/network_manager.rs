@@ -0,0 +1,473 @@
+use super::{AccessPoint, LinkStats, Persistence, Status, WifiBackend};
+use async_trait::async_trait;
+use dbus::arg::{prop_cast, PropMap, Variant};
+use dbus::nonblock::stdintf::org_freedesktop_dbus::Properties;
+use dbus::nonblock::{Proxy, SyncConnection};
+use dbus::Path;
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+const NM_BUS: &str = "org.freedesktop.NetworkManager";
+const NM_PATH: &str = "/org/freedesktop/NetworkManager";
+const NM_SETTINGS_PATH: &str = "/org/freedesktop/NetworkManager/Settings";
+const NM_IFACE: &str = "org.freedesktop.NetworkManager";
+const NM_SETTINGS_IFACE: &str = "org.freedesktop.NetworkManager.Settings";
+const NM_CONNECTION_IFACE: &str = "org.freedesktop.NetworkManager.Settings.Connection";
+const NM_DEVICE_IFACE: &str = "org.freedesktop.NetworkManager.Device";
+const NM_WIRELESS_IFACE: &str = "org.freedesktop.NetworkManager.Device.Wireless";
+const NM_AP_IFACE: &str = "org.freedesktop.NetworkManager.AccessPoint";
+const NM_IP4CONFIG_IFACE: &str = "org.freedesktop.NetworkManager.IP4Config";
+const DBUS_TIMEOUT: Duration = Duration::from_secs(10);
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const SCAN_TIMEOUT: Duration = Duration::from_secs(10);
+// connection profile id used for the network configured via BLE
+pub const CONNECTION_ID: &str = "wifi-commissioning-gatt";
+
+// NMDeviceState
+const NM_DEVICE_STATE_IP_CONFIG: u32 = 70;
+const NM_DEVICE_STATE_ACTIVATED: u32 = 100;
+
+// NM80211ApFlags
+const NM_802_11_AP_FLAGS_PRIVACY: u32 = 0x1;
+const NM_802_11_AP_FLAGS_WPS: u32 = 0x2;
+
+// NM80211ApSecurityFlags
+const NM_802_11_AP_SEC_PAIR_TKIP: u32 = 0x4;
+const NM_802_11_AP_SEC_PAIR_CCMP: u32 = 0x8;
+const NM_802_11_AP_SEC_KEY_MGMT_PSK: u32 = 0x100;
+const NM_802_11_AP_SEC_KEY_MGMT_802_1X: u32 = 0x200;
+const NM_802_11_AP_SEC_KEY_MGMT_SAE: u32 = 0x400;
+const NM_802_11_AP_SEC_KEY_MGMT_OWE: u32 = 0x800;
+
+type Settings = HashMap<String, PropMap>;
+// settings of the IP configuration, kept when the profile is replaced
+const IP_SETTINGS: [&str; 2] = ["ipv4", "ipv6"];
+// deprecated properties, NetworkManager ignores their replacements
+// (address-data, route-data) if they are given
+const DEPRECATED_IP_PROPERTIES: [&str; 2] = ["addresses", "routes"];
+
+// Inverse of NetworkManager's mapping of dBm in [-100, -40] to a 0..100 strength.
+fn strength_to_dbm(strength: u8) -> i32 {
+    -40 - (100 - strength.min(100) as i32) * 60 / 100
+}
+
+fn security_flags(prefix: &str, sec_flags: u32) -> String {
+    let key_mgmt: Vec<&str> = [
+        (NM_802_11_AP_SEC_KEY_MGMT_802_1X, "EAP"),
+        (NM_802_11_AP_SEC_KEY_MGMT_PSK, "PSK"),
+        (NM_802_11_AP_SEC_KEY_MGMT_SAE, "SAE"),
+        (NM_802_11_AP_SEC_KEY_MGMT_OWE, "OWE"),
+    ]
+    .iter()
+    .filter(|(flag, _)| sec_flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect();
+    let ciphers: Vec<&str> = [
+        (NM_802_11_AP_SEC_PAIR_CCMP, "CCMP"),
+        (NM_802_11_AP_SEC_PAIR_TKIP, "TKIP"),
+    ]
+    .iter()
+    .filter(|(flag, _)| sec_flags & flag != 0)
+    .map(|(_, name)| *name)
+    .collect();
+    format!("[{}-{}-{}]", prefix, key_mgmt.join("+"), ciphers.join("+"))
+}
+
+// Translates the NetworkManager access point flags to wpa_supplicant notation.
+fn wpa_flags(flags: u32, wpa_flags: u32, rsn_flags: u32) -> String {
+    let mut out = String::new();
+    if wpa_flags != 0 {
+        out += &security_flags("WPA", wpa_flags);
+    }
+    if rsn_flags != 0 {
+        out += &security_flags("WPA2", rsn_flags);
+    }
+    if wpa_flags == 0 && rsn_flags == 0 && flags & NM_802_11_AP_FLAGS_PRIVACY != 0 {
+        out += "[WEP]";
+    }
+    if flags & NM_802_11_AP_FLAGS_WPS != 0 {
+        out += "[WPS]";
+    }
+    out += "[ESS]";
+    out
+}
+
+fn connection_settings(ssid: Vec<u8>, psk: Vec<u8>, ip_settings: Settings) -> Settings {
+    let mut psk_hex: String = String::new();
+    for byte in psk {
+        psk_hex = psk_hex + &format!("{:02x}", byte);
+    }
+
+    let mut connection = PropMap::new();
+    connection.insert(
+        "id".to_string(),
+        Variant(Box::new(CONNECTION_ID.to_string())),
+    );
+    connection.insert(
+        "type".to_string(),
+        Variant(Box::new("802-11-wireless".to_string())),
+    );
+    let mut wireless = PropMap::new();
+    wireless.insert("ssid".to_string(), Variant(Box::new(ssid)));
+    wireless.insert(
+        "mode".to_string(),
+        Variant(Box::new("infrastructure".to_string())),
+    );
+    let mut security = PropMap::new();
+    security.insert(
+        "key-mgmt".to_string(),
+        Variant(Box::new("wpa-psk".to_string())),
+    );
+    security.insert("psk".to_string(), Variant(Box::new(psk_hex)));
+
+    let mut settings = Settings::new();
+    settings.insert("connection".to_string(), connection);
+    settings.insert("802-11-wireless".to_string(), wireless);
+    settings.insert("802-11-wireless-security".to_string(), security);
+    settings.extend(ip_settings);
+    settings
+}
+
+// The IP settings of a profile, without the deprecated properties.
+fn ip_settings(mut settings: Settings) -> Settings {
+    settings.retain(|name, _| IP_SETTINGS.contains(&name.as_str()));
+    for properties in settings.values_mut() {
+        properties.retain(|name, _| !DEPRECATED_IP_PROPERTIES.contains(&name.as_str()));
+    }
+    settings
+}
+
+pub struct NetworkManager {
+    connection: Arc<SyncConnection>,
+    interface: String,
+    persistence: Persistence,
+}
+
+impl NetworkManager {
+    pub fn new(interface: String, persistence: Persistence) -> Result<NetworkManager, dbus::Error> {
+        let (resource, connection) = dbus_tokio::connection::new_system_sync()?;
+        tokio::spawn(async move {
+            let err = resource.await;
+            error!("Lost connection to D-Bus: {}", err);
+        });
+        Ok(NetworkManager {
+            connection,
+            interface,
+            persistence,
+        })
+    }
+
+    fn proxy<'a>(&self, path: Path<'a>) -> Proxy<'a, Arc<SyncConnection>> {
+        Proxy::new(NM_BUS, path, DBUS_TIMEOUT, self.connection.clone())
+    }
+
+    async fn device(&self) -> Result<Path<'static>, String> {
+        let (device,): (Path<'static>,) = self
+            .proxy(NM_PATH.into())
+            .method_call(NM_IFACE, "GetDeviceByIpIface", (self.interface.as_str(),))
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(device)
+    }
+
+    // Removes the connection profiles previously created by this service and
+    // returns their IP settings, which may have been set by the IP config service.
+    async fn remove_connections(&self) -> Result<Settings, String> {
+        let (paths,): (Vec<Path<'static>>,) = self
+            .proxy(NM_SETTINGS_PATH.into())
+            .method_call(NM_SETTINGS_IFACE, "ListConnections", ())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut kept = Settings::new();
+        for path in paths {
+            let proxy = self.proxy(path);
+            let (settings,): (Settings,) = proxy
+                .method_call(NM_CONNECTION_IFACE, "GetSettings", ())
+                .await
+                .map_err(|e| e.to_string())?;
+            let ours = settings
+                .get("connection")
+                .and_then(|c| prop_cast::<String>(c, "id"))
+                .is_some_and(|id| id == CONNECTION_ID);
+            if ours {
+                kept = ip_settings(settings);
+                info!("Removing connection {}", &proxy.path);
+                proxy
+                    .method_call::<(), _, _, _>(NM_CONNECTION_IFACE, "Delete", ())
+                    .await
+                    .map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(kept)
+    }
+}
+
+#[async_trait]
+impl WifiBackend for NetworkManager {
+    async fn scan(&self) -> Result<Vec<AccessPoint>, String> {
+        let device = self.proxy(self.device().await?);
+        // milliseconds since boot when the last scan finished, -1 if never
+        let last_scan = || async {
+            device
+                .get::<i64>(NM_WIRELESS_IFACE, "LastScan")
+                .await
+                .map_err(|e| e.to_string())
+        };
+        let previous_scan = last_scan().await?;
+        info!("Starting SSID scan");
+        if let Err(e) = device
+            .method_call::<(), _, _, _>(NM_WIRELESS_IFACE, "RequestScan", (PropMap::new(),))
+            .await
+        {
+            // most likely a scan is already in progress
+            warn!("RequestScan failed: {}", e);
+        }
+        let scan = async {
+            loop {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                if last_scan().await? != previous_scan {
+                    return Ok::<(), String>(());
+                }
+            }
+        };
+        tokio::time::timeout(SCAN_TIMEOUT, scan)
+            .await
+            .map_err(|_| "Scan timed out.".to_string())??;
+        let (paths,): (Vec<Path<'static>>,) = device
+            .method_call(NM_WIRELESS_IFACE, "GetAllAccessPoints", ())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut aps = vec![];
+        for path in paths {
+            let ap = self.proxy(path);
+            let ssid: Vec<u8> = ap
+                .get(NM_AP_IFACE, "Ssid")
+                .await
+                .map_err(|e| e.to_string())?;
+            let bssid: String = ap
+                .get(NM_AP_IFACE, "HwAddress")
+                .await
+                .map_err(|e| e.to_string())?;
+            let frequency: u32 = ap
+                .get(NM_AP_IFACE, "Frequency")
+                .await
+                .map_err(|e| e.to_string())?;
+            let strength: u8 = ap
+                .get(NM_AP_IFACE, "Strength")
+                .await
+                .map_err(|e| e.to_string())?;
+            let flags: u32 = ap
+                .get(NM_AP_IFACE, "Flags")
+                .await
+                .map_err(|e| e.to_string())?;
+            let wpa: u32 = ap
+                .get(NM_AP_IFACE, "WpaFlags")
+                .await
+                .map_err(|e| e.to_string())?;
+            let rsn: u32 = ap
+                .get(NM_AP_IFACE, "RsnFlags")
+                .await
+                .map_err(|e| e.to_string())?;
+            aps.push(AccessPoint {
+                bssid: bssid.to_lowercase(),
+                frequency,
+                signal: strength_to_dbm(strength),
+                flags: wpa_flags(flags, wpa, rsn),
+                ssid,
+            });
+        }
+        info!("Finished SSID scan");
+        Ok(aps)
+    }
+
+    async fn connect(&self, ssid: Vec<u8>, psk: Vec<u8>) -> Result<(), String> {
+        let device = self.device().await?;
+        let ip_settings = self.remove_connections().await?;
+        let persist = match self.persistence {
+            Persistence::Memory => "memory",
+            // NetworkManager always keeps its connection profiles in separate files
+            Persistence::DropIn(_) | Persistence::SaveConfig => "disk",
+        };
+        let mut options = PropMap::new();
+        options.insert(
+            "persist".to_string(),
+            Variant(Box::new(persist.to_string())),
+        );
+        // the additional result dictionary is not needed and thus not read
+        let (connection, active): (Path<'static>, Path<'static>) = self
+            .proxy(NM_PATH.into())
+            .method_call(
+                NM_IFACE,
+                "AddAndActivateConnection2",
+                (
+                    connection_settings(ssid, psk, ip_settings),
+                    device,
+                    Path::from("/"),
+                    options,
+                ),
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+        info!("Activating connection {} as {}", connection, active);
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        self.proxy(self.device().await?)
+            .method_call::<(), _, _, _>(NM_DEVICE_IFACE, "Disconnect", ())
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn status(&self) -> Result<Status, String> {
+        let device = self.proxy(self.device().await?);
+        let state: u32 = device
+            .get(NM_DEVICE_IFACE, "State")
+            .await
+            .map_err(|e| e.to_string())?;
+        let mac_address: String = device
+            .get(NM_DEVICE_IFACE, "HwAddress")
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut status = Status {
+            // IP configuration only starts after the wifi link is up
+            completed: (NM_DEVICE_STATE_IP_CONFIG..=NM_DEVICE_STATE_ACTIVATED).contains(&state),
+            mac_address: Some(mac_address.to_lowercase()),
+            ..Default::default()
+        };
+        let ap: Path<'static> = device
+            .get(NM_WIRELESS_IFACE, "ActiveAccessPoint")
+            .await
+            .map_err(|e| e.to_string())?;
+        if status.completed && &*ap != "/" {
+            let ap = self.proxy(ap);
+            let bssid: String = ap
+                .get(NM_AP_IFACE, "HwAddress")
+                .await
+                .map_err(|e| e.to_string())?;
+            let strength: u8 = ap
+                .get(NM_AP_IFACE, "Strength")
+                .await
+                .map_err(|e| e.to_string())?;
+            status.bssid = Some(bssid.to_lowercase());
+            status.ssid = ap.get(NM_AP_IFACE, "Ssid").await.ok();
+            status.frequency = ap.get(NM_AP_IFACE, "Frequency").await.ok();
+            status.signal = Some(strength_to_dbm(strength));
+        }
+        let ip4config: Path<'static> = device
+            .get(NM_DEVICE_IFACE, "Ip4Config")
+            .await
+            .map_err(|e| e.to_string())?;
+        if status.completed && &*ip4config != "/" {
+            let addresses: Vec<PropMap> = self
+                .proxy(ip4config)
+                .get(NM_IP4CONFIG_IFACE, "AddressData")
+                .await
+                .map_err(|e| e.to_string())?;
+            status.ip_address = addresses
+                .first()
+                .and_then(|a| prop_cast::<String>(a, "address"))
+                .cloned();
+        }
+        Ok(status)
+    }
+
+    async fn known_networks(&self) -> Result<Vec<Vec<u8>>, String> {
+        let (paths,): (Vec<Path<'static>>,) = self
+            .proxy(NM_SETTINGS_PATH.into())
+            .method_call(NM_SETTINGS_IFACE, "ListConnections", ())
+            .await
+            .map_err(|e| e.to_string())?;
+        let mut ssids = vec![];
+        for path in paths {
+            let (settings,): (Settings,) = self
+                .proxy(path)
+                .method_call(NM_CONNECTION_IFACE, "GetSettings", ())
+                .await
+                .map_err(|e| e.to_string())?;
+            // only wireless connections have an SSID
+            if let Some(ssid) = settings
+                .get("802-11-wireless")
+                .and_then(|w| prop_cast::<Vec<u8>>(w, "ssid"))
+            {
+                ssids.push(ssid.clone());
+            }
+        }
+        Ok(ssids)
+    }
+
+    async fn link_stats(&self) -> Result<LinkStats, String> {
+        let device = self.proxy(self.device().await?);
+        let ap: Path<'static> = device
+            .get(NM_WIRELESS_IFACE, "ActiveAccessPoint")
+            .await
+            .map_err(|e| e.to_string())?;
+        if &*ap == "/" {
+            return Err("Not connected.".to_string());
+        }
+        // bitrate in kbit/s
+        let bitrate: u32 = device
+            .get(NM_WIRELESS_IFACE, "Bitrate")
+            .await
+            .map_err(|e| e.to_string())?;
+        let ap = self.proxy(ap);
+        let strength: u8 = ap
+            .get(NM_AP_IFACE, "Strength")
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(LinkStats {
+            signal: Some(strength_to_dbm(strength)),
+            link_speed: Some(bitrate / 1000),
+            // NetworkManager doesn't expose the noise level
+            noise: None,
+            frequency: ap.get(NM_AP_IFACE, "Frequency").await.ok(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wpa_flags() {
+        assert_eq!(wpa_flags(0x0, 0x0, 0x0), "[ESS]");
+        assert_eq!(wpa_flags(0x1, 0x0, 0x0), "[WEP][ESS]");
+        assert_eq!(
+            wpa_flags(0x3, 0x10c, 0x188),
+            "[WPA-PSK-CCMP+TKIP][WPA2-PSK-CCMP][WPS][ESS]"
+        );
+        assert_eq!(wpa_flags(0x1, 0x0, 0x588), "[WPA2-PSK+SAE-CCMP][ESS]");
+        assert_eq!(strength_to_dbm(100), -40);
+        assert_eq!(strength_to_dbm(0), -100);
+    }
+
+    #[test]
+    fn test_ip_settings() {
+        let mut ipv4 = PropMap::new();
+        ipv4.insert(
+            "method".to_string(),
+            Variant(Box::new("manual".to_string())),
+        );
+        ipv4.insert("addresses".to_string(), Variant(Box::new(vec![vec![0u32]])));
+        let mut settings = connection_settings(b"ssid".to_vec(), vec![0; 32], Settings::new());
+        settings.insert("ipv4".to_string(), ipv4);
+        let settings = connection_settings(b"other".to_vec(), vec![1; 32], ip_settings(settings));
+        let mut names: Vec<&String> = settings.keys().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "802-11-wireless",
+                "802-11-wireless-security",
+                "connection",
+                "ipv4"
+            ]
+        );
+        let ipv4 = &settings["ipv4"];
+        assert_eq!(prop_cast::<String>(ipv4, "method").unwrap(), "manual");
+        assert!(!ipv4.contains_key("addresses"));
+    }
+}
@@ -0,0 +1,470 @@
+use super::{write_private, AccessPoint, LinkStats, Persistence, Status, WifiBackend};
+use async_trait::async_trait;
+use log::{info, warn};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// wpa_supplicant scans all channels, which takes longer with 5 GHz and DFS
+const SCAN_TIMEOUT: Duration = Duration::from_secs(15);
+const EVENT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+fn unescape_hex(ssid: &str) -> Vec<u8> {
+    let re = regex::bytes::Regex::new(r"\\(\\|(x([0-9a-fA-F]{2})))").unwrap();
+    let out = re.replace_all(ssid.as_bytes(), |caps: &regex::bytes::Captures| {
+        if caps[0] == [0x5Cu8, 0x5Cu8] {
+            [0x5Cu8]
+        } else {
+            [u8::from_str_radix(std::str::from_utf8(&caps[3]).unwrap(), 16).unwrap()]
+        }
+    });
+    out.to_vec()
+}
+
+pub(crate) fn parse_scan_results(aps: &str) -> Vec<AccessPoint> {
+    let re = regex::Regex::new(r"(([0-9a-fA-F]{2}:){5}[0-9a-fA-F]{2})\t([0-9]+)\t(-?[0-9]+)\t((\[[a-zA-Z0-9+-]+\])*)\t([^\n]*)\n").unwrap();
+    re.captures_iter(aps)
+        .map(|cap| AccessPoint {
+            bssid: cap[1].to_string(),
+            frequency: cap[3].parse().unwrap_or_default(),
+            signal: cap[4].parse().unwrap_or_default(),
+            flags: cap[5].to_string(),
+            ssid: unescape_hex(&cap[7]),
+        })
+        .collect()
+}
+
+// Parses the SSIDs from the reply to LIST_NETWORKS, a header line followed
+// by "<id>\t<ssid>\t<bssid>\t<flags>" for each configured network.
+fn parse_list_networks(output: &str) -> Vec<Vec<u8>> {
+    output
+        .lines()
+        .skip(1)
+        .filter_map(|line| line.split('\t').nth(1))
+        .map(unescape_hex)
+        .collect()
+}
+
+// Parses the reply to SIGNAL_POLL, e.g. "RSSI=-52\nLINKSPEED=72\nNOISE=9999\nFREQUENCY=2412".
+fn parse_signal_poll(output: &str) -> LinkStats {
+    let mut stats = LinkStats::default();
+    for line in output.lines() {
+        match line.split_once('=') {
+            Some(("RSSI", value)) => stats.signal = value.parse().ok(),
+            Some(("LINKSPEED", value)) => stats.link_speed = value.parse().ok(),
+            // 9999 means the driver doesn't report the noise level
+            Some(("NOISE", value)) => stats.noise = value.parse().ok().filter(|n| *n != 9999),
+            Some(("FREQUENCY", value)) => stats.frequency = value.parse().ok(),
+            _ => {}
+        }
+    }
+    stats
+}
+
+// Parses a ctrl_interface value as used in wpa_supplicant.conf, which is
+// either a plain directory or of the form "DIR=<directory> [GROUP=<group>]".
+pub fn parse_ctrl_dir(value: &str) -> PathBuf {
+    let dir = value
+        .split_whitespace()
+        .find_map(|item| item.strip_prefix("DIR="))
+        .unwrap_or(value);
+    PathBuf::from(dir)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::new(), |hex, byte| hex + &format!("{:02x}", byte))
+}
+
+const SSID_MAX_LENGTH: usize = 32;
+
+// Value of the SSID in SET_NETWORK requests and configuration files. The hex
+// form is used, as the quoted form neither allows invalid UTF-8 nor escaping
+// of quotes, but it can't express the empty SSID.
+fn ssid_value(ssid: &[u8]) -> Result<String, String> {
+    match ssid.len() {
+        0 => Ok("\"\"".to_string()),
+        1..=SSID_MAX_LENGTH => Ok(hex(ssid)),
+        len => Err(format!(
+            "SSID of {} bytes exceeds the maximum of {} bytes.",
+            len, SSID_MAX_LENGTH
+        )),
+    }
+}
+
+// Writes the network to a configuration file, which wpa_supplicant reads at
+// startup when passed as additional configuration file (-I).
+fn write_drop_in(path: &Path, ssid_value: &str, psk_hex: &str) -> Result<(), String> {
+    let content = format!(
+        "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+         network={{\n\
+         \tssid={}\n\
+         \tpsk={}\n\
+         }}\n",
+        ssid_value, psk_hex
+    );
+    write_private(path, &content)
+}
+
+/// Location of the wpa_supplicant control interface.
+pub enum CtrlInterface {
+    // directory containing one socket per network interface
+    Directory(PathBuf),
+    // global control interface socket (wpa_supplicant -g)
+    Global(PathBuf),
+}
+
+// Control interface connection, which prefixes requests with IFNAME= when
+// talking to the global control interface.
+struct Connection {
+    client: wpactrl::Client,
+    ifname: Option<String>,
+}
+
+impl Connection {
+    fn request(&mut self, cmd: &str) -> wpactrl::Result<String> {
+        match &self.ifname {
+            Some(ifname) => self.client.request(&format!("IFNAME={} {}", ifname, cmd)),
+            None => self.client.request(cmd),
+        }
+    }
+}
+
+// Attached control interface connection receiving the events of the
+// interface. It is only used for events, as wpactrl can't tell the
+// IFNAME= prefixed events of the global control interface from replies.
+struct Monitor {
+    client: wpactrl::ClientAttached,
+    ifname: Option<String>,
+}
+
+impl Monitor {
+    // Waits for the first of the given events and returns it.
+    fn wait_for(
+        &mut self,
+        events: &[&'static str],
+        timeout: Duration,
+    ) -> Result<&'static str, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            while let Some(message) = self.client.recv().map_err(|e| e.to_string())? {
+                // the global control interface reports the events of all interfaces
+                if let Some(ifname) = &self.ifname {
+                    if message.starts_with("IFNAME=")
+                        && !message.starts_with(&format!("IFNAME={} ", ifname))
+                    {
+                        continue;
+                    }
+                }
+                if let Some(event) = events.iter().find(|event| message.contains(*event)) {
+                    return Ok(event);
+                }
+            }
+            if Instant::now() >= deadline {
+                return Err(format!("Timeout waiting for {}", events.join(" or ")));
+            }
+            std::thread::sleep(EVENT_POLL_INTERVAL);
+        }
+    }
+}
+
+pub struct WpaSupplicant {
+    interface: String,
+    ctrl: CtrlInterface,
+    persistence: Persistence,
+    // id of the network added by the last connect, networks configured
+    // otherwise are left alone unless the configuration is saved
+    network_id: Mutex<Option<String>>,
+}
+
+impl WpaSupplicant {
+    pub fn new(interface: String, ctrl: CtrlInterface, persistence: Persistence) -> WpaSupplicant {
+        WpaSupplicant {
+            interface,
+            ctrl,
+            persistence,
+            network_id: Mutex::new(None),
+        }
+    }
+
+    fn client(&self) -> Result<Connection, String> {
+        let (path, ifname) = match &self.ctrl {
+            CtrlInterface::Directory(dir) => (dir.join(&self.interface), None),
+            CtrlInterface::Global(path) => (path.clone(), Some(self.interface.clone())),
+        };
+        let client = wpactrl::Client::builder()
+            .ctrl_path(path)
+            .open()
+            .map_err(|e| e.to_string())?;
+        Ok(Connection { client, ifname })
+    }
+
+    fn monitor(&self) -> Result<Monitor, String> {
+        let Connection { client, ifname } = self.client()?;
+        let client = client.attach().map_err(|e| e.to_string())?;
+        Ok(Monitor { client, ifname })
+    }
+}
+
+#[async_trait]
+impl WifiBackend for WpaSupplicant {
+    async fn scan(&self) -> Result<Vec<AccessPoint>, String> {
+        let mut wpa = self.client()?;
+        // attach before requesting the scan to not miss its results
+        let mut monitor = self.monitor()?;
+        let scan_task = tokio::task::spawn_blocking(move || {
+            info!("Starting SSID scan");
+            let output = wpa.request("SCAN").map_err(|e| e.to_string())?;
+            match output.trim() {
+                "FAIL" => return Err("SCAN failed.".to_string()),
+                // a scan is already running, its results will do as well
+                "FAIL-BUSY" => info!("Scan already in progress"),
+                _ => {}
+            }
+            let event = monitor.wait_for(
+                &["CTRL-EVENT-SCAN-RESULTS", "CTRL-EVENT-SCAN-FAILED"],
+                SCAN_TIMEOUT,
+            )?;
+            if event == "CTRL-EVENT-SCAN-FAILED" {
+                return Err("Scan failed.".to_string());
+            }
+            let output = wpa.request("SCAN_RESULTS").map_err(|e| e.to_string())?;
+            if output.trim() == "FAIL" {
+                return Err("SCAN_RESULTS failed.".to_string());
+            }
+            info!("Finished SSID scan");
+            Ok(output)
+        });
+        let found_hotspots = scan_task.await.map_err(|e| e.to_string())??;
+        Ok(parse_scan_results(&found_hotspots))
+    }
+
+    async fn connect(&self, ssid: Vec<u8>, psk: Vec<u8>) -> Result<(), String> {
+        let ssid_value = ssid_value(&ssid)?;
+        let mut wpa = self.client()?;
+
+        let disconnect_response = wpa.request("DISCONNECT").map_err(|e| e.to_string())?;
+        if disconnect_response.trim() == "FAIL" {
+            return Err("Disconnect failed.".to_string());
+        }
+
+        // With save-config, the saved configuration is owned by this service and
+        // the network is expected as id 0, also after a restart.
+        let save_config = matches!(self.persistence, Persistence::SaveConfig);
+        let previous_id = if save_config {
+            Some("0".to_string())
+        } else {
+            self.network_id.lock().unwrap().take()
+        };
+        if let Some(id) = previous_id {
+            let remove_network_request = format!("REMOVE_NETWORK {}", id);
+            let remove_network_response = wpa
+                .request(&remove_network_request)
+                .map_err(|e| e.to_string())?;
+            if remove_network_response.trim() == "FAIL" {
+                warn!(
+                    "{} failed, but this is ok if there was no network in config before.",
+                    remove_network_request
+                );
+            }
+        }
+
+        let add_network_response = wpa.request("ADD_NETWORK").map_err(|e| e.to_string())?;
+        let id = add_network_response.trim().to_string();
+        if id == "FAIL" {
+            return Err("ADD_NETWORK failed.".to_string());
+        }
+        if save_config && id != "0" {
+            return Err(format!(
+                "ADD_NETWORK succeeded but returned {} instead of 0.",
+                id
+            ));
+        }
+        *self.network_id.lock().unwrap() = Some(id.clone());
+
+        let ssid_request = format!("SET_NETWORK {} ssid {}", id, ssid_value);
+        let ssid_set_response = wpa.request(&ssid_request).map_err(|e| e.to_string())?;
+        if ssid_set_response.trim() == "FAIL" {
+            return Err(format!("SET_NETWORK {} ssid failed.", id));
+        }
+
+        let psk_hex = hex(&psk);
+        let psk_request = format!("SET_NETWORK {} psk {:}", id, &psk_hex);
+        let psk_set_response = wpa.request(&psk_request).map_err(|e| e.to_string())?;
+        if psk_set_response.trim() == "FAIL" {
+            return Err(format!("SET_NETWORK {} psk failed.", id));
+        }
+
+        let select_request = format!("SELECT_NETWORK {}", id);
+        let select_response = wpa.request(&select_request).map_err(|e| e.to_string())?;
+        if select_response.trim() == "FAIL" {
+            return Err(format!("{} failed.", select_request));
+        }
+
+        match &self.persistence {
+            Persistence::Memory => {}
+            Persistence::DropIn(path) => {
+                write_drop_in(path, &ssid_value, &psk_hex)?;
+            }
+            Persistence::SaveConfig => {
+                let save_config_response = wpa.request("SAVE_CONFIG").map_err(|e| e.to_string())?;
+                if save_config_response.trim() == "FAIL" {
+                    return Err("SAVE_CONFIG failed.".to_string());
+                }
+
+                let reconfig_response = wpa.request("RECONFIGURE").map_err(|e| e.to_string())?;
+                if reconfig_response.trim() == "FAIL" {
+                    return Err("RECONFIGURE failed.".to_string());
+                }
+            }
+        }
+
+        let reconnect_response = wpa.request("RECONNECT").map_err(|e| e.to_string())?;
+        if reconnect_response.trim() == "FAIL" {
+            return Err("RECONNECT failed.".to_string());
+        }
+
+        Ok(())
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        let mut wpa = self.client()?;
+        let output = wpa.request("DISCONNECT").map_err(|e| e.to_string())?;
+        if output.trim() == "FAIL" {
+            return Err("DISCONNECT failed.".to_string());
+        }
+        Ok(())
+    }
+
+    async fn status(&self) -> Result<Status, String> {
+        let mut wpa = self.client()?;
+        let output = wpa.request("STATUS").map_err(|e| e.to_string())?;
+        if output.trim() == "FAIL" {
+            return Err("STATUS failed.".to_string());
+        }
+        let mut status = Status::default();
+        let lines = output.lines();
+        for line in lines {
+            let pair: Vec<&str> = line.splitn(2, '=').collect();
+            if pair[0] == "wpa_state" {
+                status.completed = pair[1] == "COMPLETED";
+            } else if pair[0] == "ip_address" {
+                status.ip_address = Some(pair[1].to_string());
+            } else if pair[0] == "address" {
+                status.mac_address = Some(pair[1].to_string());
+            } else if pair[0] == "bssid" {
+                status.bssid = Some(pair[1].to_string());
+            } else if pair[0] == "ssid" {
+                status.ssid = Some(unescape_hex(pair[1]));
+            } else if pair[0] == "freq" {
+                status.frequency = pair[1].parse().ok();
+            }
+        }
+        if status.completed {
+            let output = wpa.request("SIGNAL_POLL").map_err(|e| e.to_string())?;
+            status.signal = parse_signal_poll(&output).signal;
+        }
+
+        Ok(status)
+    }
+
+    async fn known_networks(&self) -> Result<Vec<Vec<u8>>, String> {
+        let mut wpa = self.client()?;
+        let output = wpa.request("LIST_NETWORKS").map_err(|e| e.to_string())?;
+        if output.trim() == "FAIL" {
+            return Err("LIST_NETWORKS failed.".to_string());
+        }
+        Ok(parse_list_networks(&output))
+    }
+
+    async fn link_stats(&self) -> Result<LinkStats, String> {
+        let mut wpa = self.client()?;
+        let output = wpa.request("SIGNAL_POLL").map_err(|e| e.to_string())?;
+        if output.trim() == "FAIL" {
+            return Err("SIGNAL_POLL failed, not connected?".to_string());
+        }
+        Ok(parse_signal_poll(&output))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unescape_hex() {
+        let v1: Vec<u8> = vec![
+            0xF0u8, 0x00u8, 0x08u8, 0x01u8, 0x5Cu8, 0x02u8, 0x0Cu8, 0x03u8, 0xFFu8, 0x0Au8, 0xf0u8,
+            0x9fu8, 0x92u8, 0xa9u8, 0x0Du8, 0xF1u8, 0x22u8, 0x09u8,
+        ];
+        let unescaped =
+            unescape_hex(r"\xF0\x00\x08\x01\\\x02\x0C\x03\xFF\x0A\xf0\x9f\x92\xa9\x0D\xF1\x22\x09");
+        assert_eq!(unescaped, v1);
+    }
+
+    #[test]
+    fn test_parse_list_networks() {
+        assert_eq!(
+            parse_list_networks(
+                "network id / ssid / bssid / flags\n0\thome\tany\t[CURRENT]\n1\t\\xf0\\x9f\tany\t[DISABLED]\n"
+            ),
+            vec![b"home".to_vec(), vec![0xf0, 0x9f]]
+        );
+        assert!(parse_list_networks("network id / ssid / bssid / flags\n").is_empty());
+    }
+
+    #[test]
+    fn test_parse_signal_poll() {
+        assert_eq!(
+            parse_signal_poll("RSSI=-52\nLINKSPEED=72\nNOISE=9999\nFREQUENCY=2412\n"),
+            LinkStats {
+                signal: Some(-52),
+                link_speed: Some(72),
+                noise: None,
+                frequency: Some(2412),
+            }
+        );
+    }
+
+    #[test]
+    fn test_ssid_value() {
+        assert_eq!(ssid_value(b"my \"ssid\"").unwrap(), "6d7920227373696422");
+        assert_eq!(ssid_value(&[0xf0, 0x00, 0xff]).unwrap(), "f000ff");
+        assert_eq!(ssid_value(b"").unwrap(), "\"\"");
+        assert_eq!(ssid_value(&[0x61; 32]).unwrap(), "61".repeat(32));
+        assert!(ssid_value(&[0x61; 33]).is_err());
+    }
+
+    #[test]
+    fn test_write_drop_in() {
+        let path = std::env::temp_dir().join(format!("drop-in-{}.conf", std::process::id()));
+        write_drop_in(&path, "6d7920227373696422", "0123").unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            content,
+            "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+             network={\n\
+             \tssid=6d7920227373696422\n\
+             \tpsk=0123\n\
+             }\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_ctrl_dir() {
+        assert_eq!(
+            parse_ctrl_dir("/run/wpa_supplicant"),
+            PathBuf::from("/run/wpa_supplicant")
+        );
+        assert_eq!(
+            parse_ctrl_dir("DIR=/run/wpa_supplicant GROUP=netdev"),
+            PathBuf::from("/run/wpa_supplicant")
+        );
+        assert_eq!(
+            parse_ctrl_dir("GROUP=netdev DIR=/var/run/wpa_supplicant"),
+            PathBuf::from("/var/run/wpa_supplicant")
+        );
+    }
+}
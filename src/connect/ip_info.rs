@@ -0,0 +1,182 @@
+use crate::backend::Status;
+use log::warn;
+
+const RESOLV_CONF: &str = "/etc/resolv.conf";
+// maximum length of a GATT attribute value
+pub(crate) const IP_INFO_MAX_LENGTH: usize = 512;
+
+/// IP configuration of the wireless interface as seen by the kernel.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct IpInfo {
+    // addresses in CIDR notation
+    pub ipv4: Vec<String>,
+    pub ipv6: Vec<String>,
+    // default gateways of both address families
+    pub gateways: Vec<String>,
+    pub dns: Vec<String>,
+}
+
+// Parses the output of "ip -o addr show".
+fn parse_addresses(output: &str, family: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .skip_while(|word| *word != family)
+                .nth(1)
+                .map(|cidr| cidr.to_string())
+        })
+        .collect()
+}
+
+// Parses the output of "ip -o route show default".
+fn parse_gateways(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            line.split_whitespace()
+                .skip_while(|word| *word != "via")
+                .nth(1)
+                .map(|gateway| gateway.to_string())
+        })
+        .collect()
+}
+
+fn parse_resolv_conf(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut words = line.split_whitespace();
+            match (words.next(), words.next()) {
+                (Some("nameserver"), Some(ip)) => Some(ip.to_string()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+async fn ip(args: &[&str]) -> Result<String, String> {
+    let output = tokio::process::Command::new("ip")
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| e.to_string())?;
+    if !output.status.success() {
+        return Err(format!(
+            "ip {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+pub async fn ip_info(interface: &str) -> Result<IpInfo, String> {
+    let addresses = ip(&["-o", "addr", "show", "dev", interface]).await?;
+    let mut gateways =
+        parse_gateways(&ip(&["-o", "route", "show", "default", "dev", interface]).await?);
+    gateways.extend(parse_gateways(
+        &ip(&["-o", "-6", "route", "show", "default", "dev", interface]).await?,
+    ));
+    let dns = match tokio::fs::read_to_string(RESOLV_CONF).await {
+        Ok(content) => parse_resolv_conf(&content),
+        Err(e) => {
+            warn!("Reading {} failed: {}", RESOLV_CONF, e);
+            vec![]
+        }
+    };
+    Ok(IpInfo {
+        ipv4: parse_addresses(&addresses, "inet"),
+        ipv6: parse_addresses(&addresses, "inet6"),
+        gateways,
+        dns,
+    })
+}
+
+fn json_list(values: &[String]) -> String {
+    let values: Vec<String> = values.iter().map(|v| format!("\"{}\"", v)).collect();
+    format!("[{}]", values.join(","))
+}
+
+pub(crate) fn json_opt<T: ToString>(value: &Option<T>) -> String {
+    value.as_ref().map(|v| v.to_string()).unwrap_or_default()
+}
+
+// All values originate from the kernel or the wifi daemon and contain no
+// characters that need escaping.
+pub fn to_json(info: &IpInfo, status: &Status) -> Vec<u8> {
+    let mut info = info.clone();
+    loop {
+        let json = format!(
+            "{{\"ipv4\":{},\"ipv6\":{},\"gw\":{},\"dns\":{},\"mac\":\"{}\",\"bssid\":\"{}\",\"freq\":\"{}\",\"rssi\":\"{}\"}}",
+            json_list(&info.ipv4),
+            json_list(&info.ipv6),
+            json_list(&info.gateways),
+            json_list(&info.dns),
+            json_opt(&status.mac_address),
+            json_opt(&status.bssid),
+            json_opt(&status.frequency),
+            json_opt(&status.signal),
+        );
+        // drop the least interesting addresses if there are too many
+        if json.len() > IP_INFO_MAX_LENGTH && info.ipv6.pop().is_some() {
+            continue;
+        }
+        return json.into_bytes();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let addresses = "3: wlan0    inet 192.168.0.2/24 brd 192.168.0.255 scope global dynamic wlan0\\       valid_lft 86380sec preferred_lft 86380sec\n\
+                         3: wlan0    inet6 fd00::2/64 scope global dynamic mngtmpaddr \\       valid_lft 7100sec preferred_lft 3500sec\n\
+                         3: wlan0    inet6 fe80::1234/64 scope link \\       valid_lft forever preferred_lft forever\n";
+        assert_eq!(parse_addresses(addresses, "inet"), vec!["192.168.0.2/24"]);
+        assert_eq!(
+            parse_addresses(addresses, "inet6"),
+            vec!["fd00::2/64", "fe80::1234/64"]
+        );
+        assert_eq!(
+            parse_gateways("default via 192.168.0.1 proto dhcp src 192.168.0.2 metric 600 \n"),
+            vec!["192.168.0.1"]
+        );
+        assert_eq!(
+            parse_resolv_conf(
+                "# comment\nnameserver 192.168.0.1\nsearch lan\nnameserver fd00::1\n"
+            ),
+            vec!["192.168.0.1", "fd00::1"]
+        );
+    }
+
+    #[test]
+    fn test_to_json() {
+        let info = IpInfo {
+            ipv4: vec!["192.168.0.2/24".to_string()],
+            ipv6: vec![],
+            gateways: vec!["192.168.0.1".to_string()],
+            dns: vec!["192.168.0.1".to_string(), "1.1.1.1".to_string()],
+        };
+        let status = Status {
+            completed: true,
+            mac_address: Some("00:11:22:33:44:55".to_string()),
+            bssid: Some("66:77:88:99:aa:bb".to_string()),
+            frequency: Some(2412),
+            signal: Some(-52),
+            ..Default::default()
+        };
+        assert_eq!(
+            String::from_utf8(to_json(&info, &status)).unwrap(),
+            "{\"ipv4\":[\"192.168.0.2/24\"],\"ipv6\":[],\"gw\":[\"192.168.0.1\"],\"dns\":[\"192.168.0.1\",\"1.1.1.1\"],\"mac\":\"00:11:22:33:44:55\",\"bssid\":\"66:77:88:99:aa:bb\",\"freq\":\"2412\",\"rssi\":\"-52\"}"
+        );
+
+        let info = IpInfo {
+            ipv6: vec!["fd00:1111:2222:3333:4444:5555:6666:7777/64".to_string(); 20],
+            ..Default::default()
+        };
+        assert!(to_json(&info, &Status::default()).len() <= IP_INFO_MAX_LENGTH);
+    }
+}
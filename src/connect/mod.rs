@@ -1,18 +1,21 @@
 use crate::authorize;
+use crate::backend::{Status, WifiBackend};
+use crate::ip_monitor::{IpState, Ready};
+use crate::notify::Notifiers;
+use crate::request::Request;
 use bluer::gatt::local::{
     characteristic_control, service_control, Characteristic, CharacteristicNotifier,
-    CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead,
-    CharacteristicReadRequest, CharacteristicWrite, CharacteristicWriteMethod,
-    CharacteristicWriteRequest, ReqError, ReqResult, Service,
+    CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+    CharacteristicWriteMethod, ReqError, ReqResult, Service,
 };
 use enclose::enclose;
 use futures::FutureExt;
 use log::{debug, error, info};
 use std::convert::TryFrom;
 use std::sync::Arc;
-use tokio::sync::Mutex;
+use tokio::sync::{watch, Mutex};
 
-pub mod interface;
+pub mod ip_info;
 
 pub const CONNECT_SERVICE_UUID: uuid::Uuid =
     uuid::Uuid::from_u128(0xd69a37ee1d8a4329bd2425db4af3c864);
@@ -21,6 +24,8 @@ const STATE_CONNECT_CHAR_UUID: uuid::Uuid =
 const SSID_CONNECT_CHAR_UUID: uuid::Uuid =
     uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa4);
 const PSK_CONNECT_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa5);
+const INFO_CONNECT_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faac);
 const SSID_MAX_LENGTH: usize = 32;
 const PSK_LENGTH: usize = 32;
 
@@ -59,35 +64,44 @@ struct ConnectSharedData {
     // When connection is finished, server will set this value to 2 or 3.
     // Client is epxected to write a 0 to disconnect from the AP.
     state_connect_value: Mutex<Vec<u8>>,
-    // Notifier instance for state_connect_value. Only one notification client is supported.
-    state_connect_notify_opt: Mutex<Option<CharacteristicNotifier>>,
+    // Notifier instances for state_connect_value
+    state_connect_notifiers: Mutex<Notifiers>,
     // SSID of the AP to connect to
     ssid_connect_value: Mutex<Vec<u8>>,
     // The PSK is expected to be 32 bytes and calculated as
     // PSK = PBKDF2(HMAC−SHA1, passphrase, ssid, 4096, 256)
     // see https://en.wikipedia.org/wiki/PBKDF2
     psk_connect_value: Mutex<Vec<u8>>,
+    // JSON object describing the IP configuration and the AP, captured when
+    // state 2 is reached and empty otherwise, e.g.
+    // {"ipv4":["192.168.0.2/24"],"ipv6":["fe80::1/64"],"gw":["192.168.0.1"],"dns":["192.168.0.1"],
+    //  "mac":"00:11:22:33:44:55","bssid":"66:77:88:99:aa:bb","freq":"2412","rssi":"-52"}
+    info_connect_value: Mutex<Vec<u8>>,
     authorized: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    backend: Arc<dyn WifiBackend + Send + Sync>,
     interface: String,
 }
 
 impl ConnectSharedData {
-    fn new(interf: String, auth: Arc<Mutex<dyn Authorized + Send + Sync>>) -> ConnectSharedData {
+    fn new(
+        backend: Arc<dyn WifiBackend + Send + Sync>,
+        interface: String,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> ConnectSharedData {
         ConnectSharedData {
             state_connect_value: Mutex::new(vec![ConnectionState::Idle as u8]),
             ssid_connect_value: Mutex::new(vec![0; SSID_MAX_LENGTH]),
             psk_connect_value: Mutex::new(vec![0; PSK_LENGTH]),
-            state_connect_notify_opt: Mutex::new(Option::None),
+            state_connect_notifiers: Mutex::new(Notifiers::default()),
+            info_connect_value: Mutex::new(vec![]),
             authorized: auth,
-            interface: interf,
+            backend,
+            interface,
         }
     }
 }
 
-async fn read_state(
-    shared: Arc<ConnectSharedData>,
-    req: CharacteristicReadRequest,
-) -> ReqResult<Vec<u8>> {
+async fn read_state(shared: Arc<ConnectSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Connect state read no auth {:?}", &req);
         return Err(ReqError::NotAuthorized);
@@ -101,7 +115,7 @@ async fn read_state(
 async fn write_state(
     shared: Arc<ConnectSharedData>,
     new_value: Vec<u8>,
-    req: CharacteristicWriteRequest,
+    req: impl Request,
 ) -> ReqResult<()> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Connect state write no auth {:?}", &req);
@@ -123,17 +137,16 @@ async fn write_state(
     let mut state_connect_value = shared.state_connect_value.lock().await;
     let old_state = ConnectionState::try_from(state_connect_value[0]).unwrap(); // this cannot fail
     state_connect_value[0] = new_state as u8;
+    shared.info_connect_value.lock().await.clear();
     match (old_state, new_state) {
         (ConnectionState::Idle | ConnectionState::Connected, ConnectionState::Connect) => {
             // connect
             let ssid_connect_value = shared.ssid_connect_value.lock().await;
             let psk_connect_value = shared.psk_connect_value.lock().await;
-            let result = interface::connect(
-                shared.interface.clone(),
-                ssid_connect_value.clone(),
-                psk_connect_value.clone(),
-            )
-            .await;
+            let result = shared
+                .backend
+                .connect(ssid_connect_value.clone(), psk_connect_value.clone())
+                .await;
             match result {
                 Err(e) => {
                     error!("Connect failed: {:?}", e);
@@ -155,7 +168,7 @@ async fn write_state(
         }
         (_old, ConnectionState::Idle) => {
             // disconnect
-            let result = interface::disconnect(shared.interface.clone()).await;
+            let result = shared.backend.disconnect().await;
             match result {
                 Err(e) => {
                     error!("Disconnect failed: {:?}", e);
@@ -172,33 +185,34 @@ async fn write_state(
         }
     };
 
-    let mut opt = shared.state_connect_notify_opt.lock().await;
-    if let Some(writer) = opt.as_mut() {
+    notify_state(&shared, &state_connect_value).await;
+    Ok(())
+}
+
+async fn notify_state(shared: &ConnectSharedData, state_connect_value: &[u8]) {
+    let mut notifiers = shared.state_connect_notifiers.lock().await;
+    if notifiers.is_active() {
         info!(
             "Notifying connect state with value {:x?}",
             &state_connect_value
         );
-        if let Err(err) = writer.notify(state_connect_value.clone()).await {
-            error!("Notification stream error: {}", &err);
-            *opt = None;
-        }
+        notifiers.notify(state_connect_value).await;
     }
-    Ok(())
 }
 
 async fn start_notify_state(shared: Arc<ConnectSharedData>, notifier: CharacteristicNotifier) {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("State connect notify no auth");
+        return;
+    }
     info!(
         "State connect accepting notify, confirming {}",
         notifier.confirming()
     );
-    let mut opt = shared.state_connect_notify_opt.lock().await;
-    *opt = Some(notifier);
+    shared.state_connect_notifiers.lock().await.add(notifier);
 }
 
-async fn read_ssid(
-    shared: Arc<ConnectSharedData>,
-    req: CharacteristicReadRequest,
-) -> ReqResult<Vec<u8>> {
+async fn read_ssid(shared: Arc<ConnectSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Connect SSID read no auth {:?}", &req);
         return Err(ReqError::NotAuthorized);
@@ -206,8 +220,8 @@ async fn read_ssid(
     let ssid_connect_value = shared.ssid_connect_value.lock().await.clone();
     info!("Connect SSID read request {:?}", &req);
     debug!(" with value {:x?}", &ssid_connect_value);
-    let offset = req.offset as usize;
-    let mtu = req.mtu as usize;
+    let offset = req.offset() as usize;
+    let mtu = req.mtu() as usize;
     if offset > ssid_connect_value.len() {
         error!("Connect SSID returning invalid offset");
         return Err(ReqError::InvalidOffset);
@@ -225,7 +239,7 @@ async fn read_ssid(
 async fn write_ssid(
     shared: Arc<ConnectSharedData>,
     new_value: Vec<u8>,
-    req: CharacteristicWriteRequest,
+    req: impl Request,
 ) -> ReqResult<()> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Connect SSID write no auth {:?}", &req);
@@ -233,7 +247,7 @@ async fn write_ssid(
     }
     info!("Connect SSID write request {:?}", &req);
     debug!(" with value {:x?}", &new_value);
-    let offset = req.offset as usize;
+    let offset = req.offset() as usize;
     let len = new_value.len();
     if len + offset > SSID_MAX_LENGTH {
         error!("Connect SSID write invalid length.");
@@ -257,7 +271,7 @@ async fn write_ssid(
 async fn write_psk(
     shared: Arc<ConnectSharedData>,
     new_value: Vec<u8>,
-    req: CharacteristicWriteRequest,
+    req: impl Request,
 ) -> ReqResult<()> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Connect PSK write no auth {:?}", &req);
@@ -265,7 +279,7 @@ async fn write_psk(
     }
     info!("Connect PSK write request {:?}", &req);
     debug!(" with value {:x?}", &new_value);
-    let offset = req.offset as usize;
+    let offset = req.offset() as usize;
     let len = new_value.len();
     if len + offset > PSK_LENGTH {
         error!("Connect PSK write invalid length.");
@@ -276,19 +290,48 @@ async fn write_psk(
     Ok(())
 }
 
+async fn read_info(shared: Arc<ConnectSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Connect info read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let info_connect_value = shared.info_connect_value.lock().await.clone();
+    info!("Connect info read request {:?}", &req);
+    debug!(" with value {:x?}", &info_connect_value);
+    let offset = req.offset() as usize;
+    let mtu = req.mtu() as usize;
+    if offset > info_connect_value.len() {
+        error!("Connect info returning invalid offset");
+        return Err(ReqError::InvalidOffset);
+    }
+    let mut size = info_connect_value.len() - offset;
+    if size > mtu {
+        size = mtu;
+    }
+    Ok(info_connect_value[offset..(offset + size)].to_vec())
+}
+
 use authorize::Authorized;
 
 pub struct ConnectService {
     shared: Arc<ConnectSharedData>,
+    ip_state: watch::Receiver<IpState>,
+    // criterion for state 2
+    ready: Ready,
 }
 
 impl ConnectService {
     pub fn new(
+        backend: Arc<dyn WifiBackend + Send + Sync>,
         interface: String,
+        ip_state: watch::Receiver<IpState>,
+        ready: Ready,
         auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
     ) -> ConnectService {
         ConnectService {
-            shared: Arc::new(ConnectSharedData::new(interface, auth)),
+            shared: Arc::new(ConnectSharedData::new(backend, interface, auth)),
+            ip_state,
+            ready,
         }
     }
     pub fn service_entry(&mut self) -> Service {
@@ -298,6 +341,7 @@ impl ConnectService {
             characteristic_control();
         let (_ssid_connect_char_control, ssid_connect_char_handle) = characteristic_control();
         let (_psk_connect_scan_char_control, psk_connect_char_handle) = characteristic_control();
+        let (_info_connect_char_control, info_connect_char_handle) = characteristic_control();
         Service {
             uuid: CONNECT_SERVICE_UUID,
             primary: true,
@@ -372,43 +416,222 @@ impl ConnectService {
                     control_handle: psk_connect_char_handle,
                     ..Default::default()
                 },
+                Characteristic {
+                    uuid: INFO_CONNECT_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_info(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    control_handle: info_connect_char_handle,
+                    ..Default::default()
+                },
             ],
             control_handle: connect_service_handle,
             ..Default::default()
         }
     }
+    pub async fn is_connected(&self) -> bool {
+        let state_connect_value = self.shared.state_connect_value.lock().await;
+        matches!(
+            ConnectionState::try_from(state_connect_value[0]),
+            Ok(ConnectionState::Connected)
+        )
+    }
     pub async fn tick(&mut self) {
         let mut notify = false;
         let mut state_connect_value = self.shared.state_connect_value.lock().await;
         if let Ok(ConnectionState::Connect) = ConnectionState::try_from(state_connect_value[0]) {
-            let result = interface::status(self.shared.interface.clone()).await;
+            let result = self.shared.backend.status().await;
             match result {
                 Err(e) => {
                     error!("Status failed: {:?}", e);
                     state_connect_value[0] = ConnectionState::Failed as u8;
                     notify = true;
                 }
-                Ok((status, ip)) => {
-                    if status == 1u8 && ip != "<unknown>" {
-                        info!("Connected with ip {:?}", ip);
-                        state_connect_value[0] = ConnectionState::Connected as u8;
-                        notify = true
-                    }
+                Ok(
+                    status @ Status {
+                        completed: true, ..
+                    },
+                ) if self.ip_state.borrow().is_ready(self.ready) => {
+                    info!(
+                        "Connected with ip {:?}, {:?}",
+                        status.ip_address,
+                        *self.ip_state.borrow()
+                    );
+                    let info = match ip_info::ip_info(&self.shared.interface).await {
+                        Ok(info) => info,
+                        Err(e) => {
+                            error!("Getting ip info failed: {:?}", e);
+                            ip_info::IpInfo::default()
+                        }
+                    };
+                    *self.shared.info_connect_value.lock().await = ip_info::to_json(&info, &status);
+                    state_connect_value[0] = ConnectionState::Connected as u8;
+                    notify = true
                 }
+                Ok(_status) => {}
             }
         }
         if notify {
-            let mut opt = self.shared.state_connect_notify_opt.lock().await;
-            if let Some(writer) = opt.as_mut() {
-                info!(
-                    "Notifying connect state with value {:x?}",
-                    &state_connect_value
-                );
-                if let Err(err) = writer.notify(state_connect_value.clone()).await {
-                    error!("Notification stream error: {}", &err);
-                    *opt = None;
-                }
-            }
+            notify_state(&self.shared, &state_connect_value).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize::MockAuthorized;
+    use crate::backend::mock::{Mock, Script};
+    use crate::request::TestRequest;
+    use std::collections::VecDeque;
+
+    fn service(
+        authorized: bool,
+        script: Script,
+    ) -> (ConnectService, Arc<Mock>, watch::Sender<IpState>) {
+        let backend = Arc::new(Mock::new(script));
+        let (ip_state, ip_state_receiver) = watch::channel(IpState::default());
+        let service = ConnectService::new(
+            backend.clone(),
+            "wlan0".to_string(),
+            ip_state_receiver,
+            Ready::Any,
+            Arc::new(Mutex::new(MockAuthorized(authorized))),
+        );
+        (service, backend, ip_state)
+    }
+
+    async fn state(service: &ConnectService) -> u8 {
+        read_state(service.shared.clone(), TestRequest::default())
+            .await
+            .unwrap()[0]
+    }
+
+    #[tokio::test]
+    async fn test_connect_waits_for_ip() {
+        let (mut service, backend, ip_state) = service(
+            true,
+            Script {
+                status: VecDeque::from([
+                    Ok(Status::default()),
+                    Ok(Status {
+                        completed: true,
+                        bssid: Some("66:77:88:99:aa:bb".to_string()),
+                        ..Default::default()
+                    }),
+                ]),
+                ..Default::default()
+            },
+        );
+        let shared = service.shared.clone();
+        write_ssid(shared.clone(), b"ssid".to_vec(), TestRequest::default())
+            .await
+            .unwrap();
+        write_psk(
+            shared.clone(),
+            vec![0x42; PSK_LENGTH],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        write_state(shared.clone(), vec![1], TestRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            backend.calls.lock().unwrap().connects,
+            vec![(b"ssid".to_vec(), vec![0x42; PSK_LENGTH])]
+        );
+        for _ in 0..3 {
+            service.tick().await;
+            assert_eq!(state(&service).await, ConnectionState::Connect as u8);
         }
+        // IPv6-only network without any IPv4 address
+        ip_state.send_replace(IpState {
+            ipv4: false,
+            ipv6: true,
+            address: true,
+        });
+        service.tick().await;
+        assert_eq!(state(&service).await, ConnectionState::Connected as u8);
+        let req = TestRequest {
+            mtu: 512,
+            ..Default::default()
+        };
+        let info = read_info(shared.clone(), req).await.unwrap();
+        assert!(String::from_utf8(info)
+            .unwrap()
+            .contains("\"bssid\":\"66:77:88:99:aa:bb\""));
+
+        write_state(shared.clone(), vec![0], TestRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(state(&service).await, ConnectionState::Idle as u8);
+        assert_eq!(backend.calls.lock().unwrap().disconnects, 1);
+        assert!(read_info(shared, TestRequest::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_connect_failures() {
+        let (mut service, backend, _ip_state) = service(
+            true,
+            Script {
+                connect: Err("rejected".to_string()),
+                status: VecDeque::from([
+                    Ok(Status::default()),
+                    Err("authentication failed".to_string()),
+                ]),
+                ..Default::default()
+            },
+        );
+        let shared = service.shared.clone();
+        assert!(matches!(
+            write_state(shared.clone(), vec![1], TestRequest::default()).await,
+            Err(ReqError::Failed)
+        ));
+        assert_eq!(state(&service).await, ConnectionState::Failed as u8);
+
+        // a new connection attempt requires a disconnect first
+        assert!(matches!(
+            write_state(shared.clone(), vec![1], TestRequest::default()).await,
+            Err(ReqError::NotSupported)
+        ));
+        write_state(shared.clone(), vec![0], TestRequest::default())
+            .await
+            .unwrap();
+
+        backend.script.lock().unwrap().connect = Ok(());
+        write_state(shared, vec![1], TestRequest::default())
+            .await
+            .unwrap();
+        service.tick().await;
+        assert_eq!(state(&service).await, ConnectionState::Connect as u8);
+        service.tick().await;
+        assert_eq!(state(&service).await, ConnectionState::Failed as u8);
+    }
+
+    #[tokio::test]
+    async fn test_not_authorized() {
+        let (service, backend, _ip_state) = service(false, Script::default());
+        let shared = service.shared.clone();
+        assert!(matches!(
+            write_ssid(shared.clone(), b"ssid".to_vec(), TestRequest::default()).await,
+            Err(ReqError::NotAuthorized)
+        ));
+        assert!(matches!(
+            write_state(shared.clone(), vec![1], TestRequest::default()).await,
+            Err(ReqError::NotAuthorized)
+        ));
+        assert!(matches!(
+            read_state(shared, TestRequest::default()).await,
+            Err(ReqError::NotAuthorized)
+        ));
+        assert!(backend.calls.lock().unwrap().connects.is_empty());
     }
 }
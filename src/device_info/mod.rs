@@ -0,0 +1,202 @@
+use crate::request::Request;
+use bluer::gatt::local::{
+    characteristic_control, service_control, Characteristic, CharacteristicRead, ReqError,
+    ReqResult, Service,
+};
+use futures::FutureExt;
+use log::{debug, info, warn};
+use std::path::Path;
+
+// Device Information Service and its characteristics as assigned by the Bluetooth SIG
+pub const DEVICE_INFO_SERVICE_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x0000180a00001000800000805f9b34fb);
+const MODEL_NUMBER_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2400001000800000805f9b34fb);
+const SERIAL_NUMBER_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2500001000800000805f9b34fb);
+const FIRMWARE_REVISION_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2600001000800000805f9b34fb);
+const SOFTWARE_REVISION_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2800001000800000805f9b34fb);
+const MANUFACTURER_NAME_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x00002a2900001000800000805f9b34fb);
+
+/// Values of the Device Information Service, characteristics without a value are left out.
+#[derive(Clone, Debug, Default)]
+pub struct DeviceInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    // must not be the BLE secret, as the service is readable without authorization
+    pub serial_number: Option<String>,
+    pub firmware_revision: Option<String>,
+}
+
+// Takes the firmware revision from an os-release style file, i.e. VERSION_ID
+// or VERSION, or else from the first line of a plain version file.
+fn parse_firmware_revision(content: &str) -> Option<String> {
+    let value = |key: &str| {
+        content.lines().find_map(|line| {
+            line.strip_prefix(key)
+                .and_then(|rest| rest.strip_prefix('='))
+                .map(|value| value.trim().trim_matches(|c| c == '"' || c == '\''))
+        })
+    };
+    let revision = match value("VERSION_ID").or_else(|| value("VERSION")) {
+        Some(revision) => revision,
+        None if !content.contains('=') => content.lines().next().unwrap_or_default().trim(),
+        None => "",
+    };
+    (!revision.is_empty()).then(|| revision.to_string())
+}
+
+pub fn firmware_revision(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(content) => parse_firmware_revision(&content),
+        Err(e) => {
+            warn!(
+                "Reading firmware revision from {} failed: {}",
+                path.display(),
+                e
+            );
+            None
+        }
+    }
+}
+
+async fn read_value(name: &str, value: &[u8], req: impl Request) -> ReqResult<Vec<u8>> {
+    info!("Device information {} read request {:?}", name, &req);
+    let offset = req.offset() as usize;
+    if offset > value.len() {
+        return Err(ReqError::InvalidOffset);
+    }
+    let size = (value.len() - offset).min(req.mtu() as usize);
+    let value = value[offset..offset + size].to_vec();
+    debug!(" with value {:x?}", &value);
+    Ok(value)
+}
+
+pub struct DeviceInfoService {
+    info: DeviceInfo,
+}
+
+impl DeviceInfoService {
+    pub fn new(info: DeviceInfo) -> DeviceInfoService {
+        DeviceInfoService { info }
+    }
+
+    fn values(&self) -> Vec<(uuid::Uuid, &'static str, String)> {
+        let info = self.info.clone();
+        [
+            (
+                MANUFACTURER_NAME_CHAR_UUID,
+                "manufacturer",
+                info.manufacturer,
+            ),
+            (MODEL_NUMBER_CHAR_UUID, "model", info.model),
+            (SERIAL_NUMBER_CHAR_UUID, "serial number", info.serial_number),
+            (
+                FIRMWARE_REVISION_CHAR_UUID,
+                "firmware revision",
+                info.firmware_revision,
+            ),
+            (
+                SOFTWARE_REVISION_CHAR_UUID,
+                "software revision",
+                Some(env!("CARGO_PKG_VERSION").to_string()),
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(uuid, name, value)| value.map(|value| (uuid, name, value)))
+        .collect()
+    }
+
+    pub fn service_entry(&mut self) -> Service {
+        let (_device_info_service_control, device_info_service_handle) = service_control();
+        let characteristics = self
+            .values()
+            .into_iter()
+            .map(|(uuid, name, value)| {
+                let (_char_control, char_handle) = characteristic_control();
+                let value = value.into_bytes();
+                Characteristic {
+                    uuid,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(move |req| {
+                            let value = value.clone();
+                            async move { read_value(name, &value, req).await }.boxed()
+                        }),
+                        ..Default::default()
+                    }),
+                    control_handle: char_handle,
+                    ..Default::default()
+                }
+            })
+            .collect();
+        Service {
+            uuid: DEVICE_INFO_SERVICE_UUID,
+            primary: true,
+            characteristics,
+            control_handle: device_info_service_handle,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::request::TestRequest;
+
+    #[test]
+    fn test_parse_firmware_revision() {
+        let os_release = "NAME=\"omnect OS\"\nVERSION=\"4.0.12 (kirkstone)\"\nVERSION_ID=4.0.12\n";
+        assert_eq!(
+            parse_firmware_revision(os_release).as_deref(),
+            Some("4.0.12")
+        );
+        assert_eq!(
+            parse_firmware_revision("NAME=x\nVERSION=\"1.2\"\n").as_deref(),
+            Some("1.2")
+        );
+        assert_eq!(parse_firmware_revision("1.2.3\n").as_deref(), Some("1.2.3"));
+        assert_eq!(parse_firmware_revision("NAME=x\n"), None);
+        assert_eq!(parse_firmware_revision(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_device_info() {
+        let service = DeviceInfoService::new(DeviceInfo {
+            manufacturer: Some("Example Inc.".to_string()),
+            serial_number: Some("0123456789abcdef0123456789abcdef".to_string()),
+            ..Default::default()
+        });
+        let values = service.values();
+        let uuids: Vec<uuid::Uuid> = values.iter().map(|(uuid, _, _)| *uuid).collect();
+        assert_eq!(
+            uuids,
+            vec![
+                MANUFACTURER_NAME_CHAR_UUID,
+                SERIAL_NUMBER_CHAR_UUID,
+                SOFTWARE_REVISION_CHAR_UUID
+            ]
+        );
+        assert_eq!(values[2].2, env!("CARGO_PKG_VERSION"));
+        let serial = values[1].2.as_bytes();
+        let part = read_value("serial number", serial, TestRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(part, serial[..23]);
+        let req = TestRequest {
+            offset: 23,
+            ..Default::default()
+        };
+        let part = read_value("serial number", serial, req).await.unwrap();
+        assert_eq!(part, serial[23..]);
+        let req = TestRequest {
+            offset: 33,
+            ..Default::default()
+        };
+        assert!(read_value("serial number", serial, req).await.is_err());
+    }
+}
@@ -0,0 +1,755 @@
+use crate::authorize;
+use crate::backend::WifiBackend;
+use crate::notify::Notifiers;
+use crate::request::Request;
+use bluer::gatt::local::{
+    characteristic_control, service_control, Characteristic, CharacteristicNotifier,
+    CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+    CharacteristicWriteMethod, ReqError, ReqResult, Service,
+};
+use enclose::enclose;
+use futures::FutureExt;
+use log::{debug, error, info};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+pub mod reachability;
+mod tools;
+
+use reachability::Checks;
+
+pub const DIAGNOSTICS_SERVICE_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0xd69a37ee1d8a4329bd2425db4af3c867);
+const REACHABILITY_DIAGNOSTICS_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faad);
+const STATE_DIAGNOSTICS_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faae);
+const NAME_DIAGNOSTICS_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faaf);
+const RESULT_DIAGNOSTICS_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab0);
+const LINK_DIAGNOSTICS_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab1);
+const SIGNAL_DIAGNOSTICS_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab2);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+enum DiagnosticsState {
+    Idle = 0u8,
+    Ping = 1u8,
+    Lookup = 2u8,
+    Done = 3u8,
+    Failed = 4u8,
+}
+
+impl std::convert::TryFrom<u8> for DiagnosticsState {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let result = match value {
+            0u8 => DiagnosticsState::Idle,
+            1u8 => DiagnosticsState::Ping,
+            2u8 => DiagnosticsState::Lookup,
+            3u8 => DiagnosticsState::Done,
+            4u8 => DiagnosticsState::Failed,
+            _ => Err(format!("invalid diagnostics state: {}", value))?,
+        };
+
+        Ok(result)
+    }
+}
+
+struct DiagnosticsSharedData {
+    // Results of the reachability checks run after each connect as JSON
+    // object, e.g. {"dns":"ok","tcp":"ok","http":"portal"}, see
+    // reachability::Results. Empty while not connected or the checks are
+    // still running.
+    reachability_diagnostics_value: Mutex<Vec<u8>>,
+    // Notifier instances for reachability_diagnostics_value
+    reachability_diagnostics_notifiers: Mutex<Notifiers>,
+    // Diagnostics state, u8
+    // 0: Idle
+    // 1: Ping the default gateway
+    // 2: Look up the name
+    // 3: Done
+    // 4: Failed
+    // Client is expected to write a 1, or a 2 after setting the name, to
+    // start a diagnostic. When it is finished, server will set this value to
+    // 3 or 4 and the result is available.
+    state_diagnostics_value: Mutex<Vec<u8>>,
+    // Notifier instances for state_diagnostics_value
+    state_diagnostics_notifiers: Mutex<Notifiers>,
+    // Host name to look up
+    name_diagnostics_value: Mutex<Vec<u8>>,
+    // Result of the last diagnostic as JSON object, empty unless state is 3
+    // ping: {"gw":"192.168.0.1","sent":"3","received":"3","rtt":"2.230"}
+    // lookup: {"name":"example.com","addresses":["93.184.216.34"]}
+    result_diagnostics_value: Mutex<Vec<u8>>,
+    // Statistics of the current link as JSON object, refreshed on each read at offset 0, e.g.
+    // {"rssi":"-52","speed":"72","noise":"","freq":"2412"}
+    link_diagnostics_value: Mutex<Vec<u8>>,
+    // Signal of the current link, refreshed on each read and notified every
+    // tick while connected, 3 bytes, 0 if unknown
+    // [0]: RSSI in dBm, i8
+    // [1..3]: link speed in Mbit/s, u16 little endian
+    signal_diagnostics_value: Mutex<Vec<u8>>,
+    // Notifier instances for signal_diagnostics_value
+    signal_diagnostics_notifiers: Mutex<Notifiers>,
+    authorized: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    checks: Checks,
+    backend: Arc<dyn WifiBackend + Send + Sync>,
+    interface: String,
+}
+
+impl DiagnosticsSharedData {
+    fn new(
+        backend: Arc<dyn WifiBackend + Send + Sync>,
+        interface: String,
+        checks: Checks,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> DiagnosticsSharedData {
+        DiagnosticsSharedData {
+            reachability_diagnostics_value: Mutex::new(vec![]),
+            reachability_diagnostics_notifiers: Mutex::new(Notifiers::default()),
+            state_diagnostics_value: Mutex::new(vec![DiagnosticsState::Idle as u8]),
+            state_diagnostics_notifiers: Mutex::new(Notifiers::default()),
+            name_diagnostics_value: Mutex::new(vec![]),
+            result_diagnostics_value: Mutex::new(vec![]),
+            link_diagnostics_value: Mutex::new(vec![]),
+            signal_diagnostics_value: Mutex::new(vec![0, 0, 0]),
+            signal_diagnostics_notifiers: Mutex::new(Notifiers::default()),
+            authorized: auth,
+            checks,
+            backend,
+            interface,
+        }
+    }
+}
+
+// Reads a variable length value, longer than the MTU values are read in several requests.
+fn read_offset(value: &[u8], req: &impl Request) -> ReqResult<Vec<u8>> {
+    let offset = req.offset() as usize;
+    let mtu = req.mtu() as usize;
+    if offset > value.len() {
+        return Err(ReqError::InvalidOffset);
+    }
+    let mut size = value.len() - offset;
+    if size > mtu {
+        size = mtu;
+    }
+    Ok(value[offset..(offset + size)].to_vec())
+}
+
+async fn read_reachability(
+    shared: Arc<DiagnosticsSharedData>,
+    req: impl Request,
+) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics reachability read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let reachability_diagnostics_value = shared.reachability_diagnostics_value.lock().await.clone();
+    info!("Diagnostics reachability read request {:?}", &req);
+    debug!(" with value {:x?}", &reachability_diagnostics_value);
+    read_offset(&reachability_diagnostics_value, &req)
+}
+
+async fn start_notify_reachability(
+    shared: Arc<DiagnosticsSharedData>,
+    notifier: CharacteristicNotifier,
+) {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics reachability notify no auth");
+        return;
+    }
+    info!(
+        "Diagnostics reachability accepting notify, confirming {}",
+        notifier.confirming()
+    );
+    shared
+        .reachability_diagnostics_notifiers
+        .lock()
+        .await
+        .add(notifier);
+}
+
+async fn check_reachability(shared: Arc<DiagnosticsSharedData>) {
+    let results = reachability::run(&shared.checks).await.to_json();
+    let mut reachability_diagnostics_value = shared.reachability_diagnostics_value.lock().await;
+    *reachability_diagnostics_value = results;
+    let mut notifiers = shared.reachability_diagnostics_notifiers.lock().await;
+    if notifiers.is_active() {
+        info!(
+            "Notifying diagnostics reachability with value {:x?}",
+            &reachability_diagnostics_value
+        );
+        notifiers.notify(&reachability_diagnostics_value).await;
+    }
+}
+
+async fn read_state(shared: Arc<DiagnosticsSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics state read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let state_diagnostics_value = shared.state_diagnostics_value.lock().await.clone();
+    info!("Diagnostics state read request {:?}", &req);
+    debug!(" with value {:x?}", &state_diagnostics_value);
+    Ok(state_diagnostics_value)
+}
+
+async fn notify_state(shared: &DiagnosticsSharedData, state_diagnostics_value: &[u8]) {
+    let mut notifiers = shared.state_diagnostics_notifiers.lock().await;
+    if notifiers.is_active() {
+        info!(
+            "Notifying diagnostics state with value {:x?}",
+            &state_diagnostics_value
+        );
+        notifiers.notify(state_diagnostics_value).await;
+    }
+}
+
+async fn run_diagnostic(shared: Arc<DiagnosticsSharedData>, state: DiagnosticsState) {
+    let result = match state {
+        DiagnosticsState::Ping => tools::ping_gateway(&shared.interface).await,
+        _ => {
+            let name = shared.name_diagnostics_value.lock().await.clone();
+            tools::lookup(&name).await
+        }
+    };
+    let mut state_diagnostics_value = shared.state_diagnostics_value.lock().await;
+    match result {
+        Err(e) => {
+            error!("Diagnostic {:?} failed: {}", state, e);
+            state_diagnostics_value[0] = DiagnosticsState::Failed as u8;
+        }
+        Ok(result) => {
+            info!("Diagnostic {:?} finished", state);
+            *shared.result_diagnostics_value.lock().await = result;
+            state_diagnostics_value[0] = DiagnosticsState::Done as u8;
+        }
+    }
+    notify_state(&shared, &state_diagnostics_value).await;
+}
+
+async fn write_state(
+    shared: Arc<DiagnosticsSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics state write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Diagnostics state write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    if new_value.len() != 1 {
+        error!("Diagnostics state write invalid length.");
+        return Err(ReqError::InvalidValueLength);
+    }
+    let new_state = match DiagnosticsState::try_from(new_value[0]) {
+        Ok(
+            state @ (DiagnosticsState::Idle | DiagnosticsState::Ping | DiagnosticsState::Lookup),
+        ) => state,
+        _ => {
+            error!("Diagnostics state write invalid state, expected 0, 1 or 2.");
+            return Err(ReqError::NotSupported);
+        }
+    };
+    let mut state_diagnostics_value = shared.state_diagnostics_value.lock().await;
+    if let Ok(DiagnosticsState::Ping | DiagnosticsState::Lookup) =
+        DiagnosticsState::try_from(state_diagnostics_value[0])
+    {
+        error!("Diagnostics state write while a diagnostic is running.");
+        return Err(ReqError::InProgress);
+    }
+    shared.result_diagnostics_value.lock().await.clear();
+    state_diagnostics_value[0] = new_state as u8;
+    if new_state != DiagnosticsState::Idle {
+        // ping and lookup take seconds, so don't block the request
+        tokio::spawn(run_diagnostic(shared.clone(), new_state));
+    }
+    Ok(())
+}
+
+async fn start_notify_state(shared: Arc<DiagnosticsSharedData>, notifier: CharacteristicNotifier) {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics state notify no auth");
+        return;
+    }
+    info!(
+        "Diagnostics state accepting notify, confirming {}",
+        notifier.confirming()
+    );
+    shared
+        .state_diagnostics_notifiers
+        .lock()
+        .await
+        .add(notifier);
+}
+
+async fn read_name(shared: Arc<DiagnosticsSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics name read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let name_diagnostics_value = shared.name_diagnostics_value.lock().await.clone();
+    info!("Diagnostics name read request {:?}", &req);
+    debug!(" with value {:x?}", &name_diagnostics_value);
+    read_offset(&name_diagnostics_value, &req)
+}
+
+async fn write_name(
+    shared: Arc<DiagnosticsSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics name write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Diagnostics name write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    let offset = req.offset() as usize;
+    let len = new_value.len();
+    if len + offset > tools::NAME_MAX_LENGTH {
+        error!("Diagnostics name write invalid length.");
+        return Err(ReqError::InvalidValueLength);
+    }
+    let mut name_diagnostics_value = shared.name_diagnostics_value.lock().await;
+    // variable length like the SSID, see connect service
+    if offset == 0 {
+        name_diagnostics_value.clear();
+    }
+    if offset > name_diagnostics_value.len() {
+        error!("Diagnostics name write invalid offset.");
+        return Err(ReqError::InvalidOffset);
+    }
+    let endoffset = (offset + len).min(name_diagnostics_value.len());
+    name_diagnostics_value.splice(offset..endoffset, new_value.iter().cloned());
+    Ok(())
+}
+
+async fn read_result(shared: Arc<DiagnosticsSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics result read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let result_diagnostics_value = shared.result_diagnostics_value.lock().await.clone();
+    info!("Diagnostics result read request {:?}", &req);
+    debug!(" with value {:x?}", &result_diagnostics_value);
+    read_offset(&result_diagnostics_value, &req)
+}
+
+async fn read_link(shared: Arc<DiagnosticsSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics link read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Diagnostics link read request {:?}", &req);
+    let mut link_diagnostics_value = shared.link_diagnostics_value.lock().await;
+    // keep the value stable while the client reads the remaining parts
+    if req.offset() == 0 {
+        match shared.backend.link_stats().await {
+            Ok(stats) => *link_diagnostics_value = tools::link_stats_json(&stats),
+            Err(e) => {
+                error!("Getting link statistics failed: {}", e);
+                link_diagnostics_value.clear();
+                return Err(ReqError::Failed);
+            }
+        }
+    }
+    debug!(" with value {:x?}", &link_diagnostics_value);
+    read_offset(&link_diagnostics_value, &req)
+}
+
+async fn read_signal(shared: Arc<DiagnosticsSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics signal read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Diagnostics signal read request {:?}", &req);
+    let mut signal_diagnostics_value = shared.signal_diagnostics_value.lock().await;
+    match shared.backend.link_stats().await {
+        Ok(stats) => *signal_diagnostics_value = tools::signal_value(&stats),
+        Err(e) => {
+            error!("Getting link statistics failed: {}", e);
+            return Err(ReqError::Failed);
+        }
+    }
+    debug!(" with value {:x?}", &signal_diagnostics_value);
+    Ok(signal_diagnostics_value.clone())
+}
+
+async fn start_notify_signal(shared: Arc<DiagnosticsSharedData>, notifier: CharacteristicNotifier) {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Diagnostics signal notify no auth");
+        return;
+    }
+    info!(
+        "Diagnostics signal accepting notify, confirming {}",
+        notifier.confirming()
+    );
+    shared
+        .signal_diagnostics_notifiers
+        .lock()
+        .await
+        .add(notifier);
+}
+
+async fn notify_signal(shared: &DiagnosticsSharedData) {
+    let mut notifiers = shared.signal_diagnostics_notifiers.lock().await;
+    // only poll the wifi daemon while a client is subscribed
+    if !notifiers.is_active() {
+        return;
+    }
+    let stats = match shared.backend.link_stats().await {
+        Ok(stats) => stats,
+        Err(e) => {
+            debug!("Getting link statistics failed: {}", e);
+            return;
+        }
+    };
+    let mut signal_diagnostics_value = shared.signal_diagnostics_value.lock().await;
+    *signal_diagnostics_value = tools::signal_value(&stats);
+    debug!(
+        "Notifying diagnostics signal with value {:x?}",
+        &signal_diagnostics_value
+    );
+    notifiers.notify(&signal_diagnostics_value).await;
+}
+
+use authorize::Authorized;
+
+pub struct DiagnosticsService {
+    shared: Arc<DiagnosticsSharedData>,
+    connected: bool,
+    reachability_task: Option<JoinHandle<()>>,
+}
+
+impl DiagnosticsService {
+    pub fn new(
+        backend: Arc<dyn WifiBackend + Send + Sync>,
+        interface: String,
+        checks: Checks,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> DiagnosticsService {
+        DiagnosticsService {
+            shared: Arc::new(DiagnosticsSharedData::new(backend, interface, checks, auth)),
+            connected: false,
+            reachability_task: None,
+        }
+    }
+    pub fn service_entry(&mut self) -> Service {
+        let shared = self.shared.clone();
+        let (_diagnostics_service_control, diagnostics_service_handle) = service_control();
+        let (_reachability_diagnostics_char_control, reachability_diagnostics_char_handle) =
+            characteristic_control();
+        let (_state_diagnostics_char_control, state_diagnostics_char_handle) =
+            characteristic_control();
+        let (_name_diagnostics_char_control, name_diagnostics_char_handle) =
+            characteristic_control();
+        let (_result_diagnostics_char_control, result_diagnostics_char_handle) =
+            characteristic_control();
+        let (_link_diagnostics_char_control, link_diagnostics_char_handle) =
+            characteristic_control();
+        let (_signal_diagnostics_char_control, signal_diagnostics_char_handle) =
+            characteristic_control();
+        Service {
+            uuid: DIAGNOSTICS_SERVICE_UUID,
+            primary: true,
+            characteristics: vec![
+                Characteristic {
+                    uuid: REACHABILITY_DIAGNOSTICS_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_reachability(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(
+                            enclose!( (shared) move|notifier| {
+                                let shared = shared.clone();
+                                start_notify_reachability(shared, notifier).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: reachability_diagnostics_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: STATE_DIAGNOSTICS_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_state(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_state(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(
+                            enclose!( (shared) move|notifier| {
+                                let shared = shared.clone();
+                                start_notify_state(shared, notifier).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: state_diagnostics_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: NAME_DIAGNOSTICS_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_name(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_name(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: name_diagnostics_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: RESULT_DIAGNOSTICS_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_result(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    control_handle: result_diagnostics_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: LINK_DIAGNOSTICS_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_link(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    control_handle: link_diagnostics_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: SIGNAL_DIAGNOSTICS_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_signal(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(
+                            enclose!( (shared) move|notifier| {
+                                let shared = shared.clone();
+                                start_notify_signal(shared, notifier).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: signal_diagnostics_char_handle,
+                    ..Default::default()
+                },
+            ],
+            control_handle: diagnostics_service_handle,
+            ..Default::default()
+        }
+    }
+    // Called from the main loop with the current connect state.
+    pub async fn tick(&mut self, connected: bool) {
+        if connected && !self.connected {
+            info!("Connected, checking reachability");
+            // the checks take several seconds if the network is unusable, so
+            // don't block the main loop
+            self.reachability_task = Some(tokio::spawn(check_reachability(self.shared.clone())));
+        } else if !connected && self.connected {
+            if let Some(task) = self.reachability_task.take() {
+                task.abort();
+            }
+            self.shared
+                .reachability_diagnostics_value
+                .lock()
+                .await
+                .clear();
+        }
+        self.connected = connected;
+        if connected {
+            // lets installers watch the signal while repositioning the device
+            notify_signal(&self.shared).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize::MockAuthorized;
+    use crate::backend::mock::{Mock, Script};
+    use crate::backend::LinkStats;
+    use crate::request::TestRequest;
+
+    fn service(script: Script) -> DiagnosticsService {
+        DiagnosticsService::new(
+            Arc::new(Mock::new(script)),
+            "wlan0".to_string(),
+            Checks::default(),
+            Arc::new(Mutex::new(MockAuthorized(true))),
+        )
+    }
+
+    async fn state(shared: &Arc<DiagnosticsSharedData>) -> u8 {
+        read_state(shared.clone(), TestRequest::default())
+            .await
+            .unwrap()[0]
+    }
+
+    #[tokio::test]
+    async fn test_reachability() {
+        let mut service = service(Script::default());
+        let shared = service.shared.clone();
+        service.tick(true).await;
+        service.reachability_task.take().unwrap().await.unwrap();
+        assert_eq!(
+            read_reachability(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            b"{}"
+        );
+        service.tick(true).await;
+        assert!(service.reachability_task.is_none());
+        service.tick(false).await;
+        assert!(read_reachability(shared, TestRequest::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        let shared = service(Script::default()).shared;
+        write_name(shared.clone(), b"local".to_vec(), TestRequest::default())
+            .await
+            .unwrap();
+        let req = TestRequest {
+            offset: 5,
+            ..Default::default()
+        };
+        write_name(shared.clone(), b"host".to_vec(), req)
+            .await
+            .unwrap();
+        write_state(shared.clone(), vec![2], TestRequest::default())
+            .await
+            .unwrap();
+        while state(&shared).await == DiagnosticsState::Lookup as u8 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(state(&shared).await, DiagnosticsState::Done as u8);
+        let req = TestRequest {
+            mtu: 512,
+            ..Default::default()
+        };
+        let result = read_result(shared.clone(), req).await.unwrap();
+        assert!(result.starts_with(b"{\"name\":\"localhost\""));
+
+        write_name(
+            shared.clone(),
+            b"no spaces".to_vec(),
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        write_state(shared.clone(), vec![2], TestRequest::default())
+            .await
+            .unwrap();
+        while state(&shared).await == DiagnosticsState::Lookup as u8 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(state(&shared).await, DiagnosticsState::Failed as u8);
+        assert!(read_result(shared, TestRequest::default())
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_link() {
+        let shared = service(Script {
+            link_stats: Ok(LinkStats {
+                signal: Some(-52),
+                link_speed: Some(72),
+                noise: None,
+                frequency: Some(2412),
+            }),
+            ..Default::default()
+        })
+        .shared;
+        // read in MTU sized parts like a client does
+        let mut value = vec![];
+        loop {
+            let req = TestRequest {
+                offset: value.len() as u16,
+                ..Default::default()
+            };
+            let part = read_link(shared.clone(), req).await.unwrap();
+            if part.is_empty() {
+                break;
+            }
+            value.extend(part);
+        }
+        assert_eq!(
+            value,
+            b"{\"rssi\":\"-52\",\"speed\":\"72\",\"noise\":\"\",\"freq\":\"2412\"}"
+        );
+
+        assert_eq!(
+            read_signal(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![0xcc, 72, 0]
+        );
+
+        let shared = service(Script {
+            link_stats: Err("not connected".to_string()),
+            ..Default::default()
+        })
+        .shared;
+        assert!(matches!(
+            read_link(shared, TestRequest::default()).await,
+            Err(ReqError::Failed)
+        ));
+    }
+}
@@ -0,0 +1,285 @@
+use log::{info, warn};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+const CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+// only the status line and headers of the HTTP response are of interest
+const HTTP_RESPONSE_MAX_LENGTH: usize = 1024;
+
+/// Checks run after a connection was established, each only if configured.
+#[derive(Clone, Debug, Default)]
+pub struct Checks {
+    // host name to resolve
+    pub dns: Option<String>,
+    // host:port to open a TCP connection to
+    pub tcp: Option<String>,
+    // plain http URL expected to answer with 204 No Content
+    pub http: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Outcome {
+    Ok,
+    Failed,
+    // the HTTP probe was redirected or got a page instead of 204, most
+    // likely from a captive portal
+    Portal,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Ok => "ok",
+            Outcome::Failed => "failed",
+            Outcome::Portal => "portal",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Results {
+    pub dns: Option<Outcome>,
+    pub tcp: Option<Outcome>,
+    pub http: Option<Outcome>,
+}
+
+impl Results {
+    // JSON object with the outcome of each configured check, e.g.
+    // {"dns":"ok","tcp":"ok","http":"portal"}
+    pub fn to_json(&self) -> Vec<u8> {
+        let fields: Vec<String> = [("dns", self.dns), ("tcp", self.tcp), ("http", self.http)]
+            .iter()
+            .filter_map(|(name, outcome)| {
+                outcome.map(|outcome| format!("\"{}\":\"{}\"", name, outcome.as_str()))
+            })
+            .collect();
+        format!("{{{}}}", fields.join(",")).into_bytes()
+    }
+}
+
+// Splits "http://host[:port][/path]" into the address to connect to, the
+// host header and the path.
+fn parse_url(url: &str) -> Result<(String, String, String), String> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or(format!("only http URLs are supported: {}", url))?;
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], &rest[index..]),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        return Err(format!("missing host: {}", url));
+    }
+    // IPv6 literals contain colons themselves, e.g. "[fd00::1]:8080"
+    let address = if host
+        .rsplit_once(':')
+        .is_some_and(|(_, port)| !port.contains(']'))
+    {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    };
+    Ok((address, host.to_string(), path.to_string()))
+}
+
+async fn check_dns(name: &str) -> Result<(), String> {
+    let addresses: Vec<_> = tokio::net::lookup_host((name, 0))
+        .await
+        .map_err(|e| e.to_string())?
+        .collect();
+    info!("Resolved {} to {:?}", name, addresses);
+    Ok(())
+}
+
+async fn check_tcp(address: &str) -> Result<(), String> {
+    TcpStream::connect(address)
+        .await
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+async fn check_http(url: &str) -> Result<Outcome, String> {
+    let (address, host, path) = parse_url(url)?;
+    let mut stream = TcpStream::connect(&address)
+        .await
+        .map_err(|e| e.to_string())?;
+    let request = format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, host
+    );
+    stream
+        .write_all(request.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    let mut response = vec![];
+    stream
+        .take(HTTP_RESPONSE_MAX_LENGTH as u64)
+        .read_to_end(&mut response)
+        .await
+        .map_err(|e| e.to_string())?;
+    let outcome = parse_response(&String::from_utf8_lossy(&response))?;
+    if outcome == Outcome::Portal {
+        warn!(
+            "HTTP probe {} got a redirect or page, assuming captive portal",
+            url
+        );
+    }
+    Ok(outcome)
+}
+
+// Captive portals redirect the probe or answer it with their login page,
+// whereas other statuses, like 404 or 503, hint at a broken probe server.
+fn parse_response(response: &str) -> Result<Outcome, String> {
+    let (head, body) = response.split_once("\r\n\r\n").unwrap_or((response, ""));
+    let mut lines = head.lines();
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .ok_or("invalid HTTP response".to_string())?;
+    let has_body = !body.is_empty()
+        || lines.any(|line| {
+            line.split_once(':').is_some_and(|(name, value)| {
+                name.eq_ignore_ascii_case("content-length")
+                    && value.trim().parse::<u64>().is_ok_and(|length| length > 0)
+            })
+        });
+    match status {
+        "204" => Ok(Outcome::Ok),
+        "200" if has_body => Ok(Outcome::Portal),
+        status if status.len() == 3 && status.starts_with('3') => Ok(Outcome::Portal),
+        status => Err(format!("HTTP probe returned {}", status)),
+    }
+}
+
+async fn with_timeout<F>(name: &str, check: F) -> Outcome
+where
+    F: std::future::Future<Output = Result<Outcome, String>>,
+{
+    match tokio::time::timeout(CHECK_TIMEOUT, check).await {
+        Ok(Ok(outcome)) => outcome,
+        Ok(Err(e)) => {
+            warn!("Reachability check {} failed: {}", name, e);
+            Outcome::Failed
+        }
+        Err(_) => {
+            warn!("Reachability check {} timed out", name);
+            Outcome::Failed
+        }
+    }
+}
+
+pub async fn run(checks: &Checks) -> Results {
+    let mut results = Results::default();
+    if let Some(name) = &checks.dns {
+        results.dns =
+            Some(with_timeout("dns", async { check_dns(name).await.map(|_| Outcome::Ok) }).await);
+    }
+    if let Some(address) = &checks.tcp {
+        results.tcp = Some(
+            with_timeout("tcp", async {
+                check_tcp(address).await.map(|_| Outcome::Ok)
+            })
+            .await,
+        );
+    }
+    if let Some(url) = &checks.http {
+        results.http = Some(with_timeout("http", check_http(url)).await);
+    }
+    info!("Reachability check results {:?}", results);
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn test_parse_url() {
+        assert_eq!(
+            parse_url("http://connectivitycheck.gstatic.com/generate_204").unwrap(),
+            (
+                "connectivitycheck.gstatic.com:80".to_string(),
+                "connectivitycheck.gstatic.com".to_string(),
+                "/generate_204".to_string()
+            )
+        );
+        assert_eq!(
+            parse_url("http://[fd00::1]:8080").unwrap(),
+            (
+                "[fd00::1]:8080".to_string(),
+                "[fd00::1]:8080".to_string(),
+                "/".to_string()
+            )
+        );
+        assert_eq!(parse_url("http://[fd00::1]/").unwrap().0, "[fd00::1]:80");
+        assert!(parse_url("https://example.com/").is_err());
+        assert!(parse_url("http:///").is_err());
+    }
+
+    #[test]
+    fn test_parse_response() {
+        assert_eq!(
+            parse_response("HTTP/1.1 204 No Content\r\nContent-Length: 0\r\n\r\n"),
+            Ok(Outcome::Ok)
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 302 Found\r\nLocation: http://portal/\r\n\r\n"),
+            Ok(Outcome::Portal)
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 200 OK\r\n\r\n<html>login</html>"),
+            Ok(Outcome::Portal)
+        );
+        assert_eq!(
+            parse_response("HTTP/1.1 200 OK\r\ncontent-length: 18\r\n\r\n"),
+            Ok(Outcome::Portal)
+        );
+        assert!(parse_response("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n").is_err());
+        assert!(parse_response("HTTP/1.1 503 Service Unavailable\r\n\r\n").is_err());
+        assert!(parse_response("HTTP/1.1 404 Not Found\r\n\r\nnot found").is_err());
+        assert!(parse_response("").is_err());
+    }
+
+    async fn serve(response: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut request = [0u8; 1024];
+                // the TCP check closes the connection right away
+                if let Ok(len) = stream.read(&mut request).await {
+                    if len > 0 {
+                        let _ = stream.write_all(response.as_bytes()).await;
+                    }
+                }
+            }
+        });
+        format!("http://{}/generate_204", address)
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let url = serve("HTTP/1.1 204 No Content\r\n\r\n").await;
+        let results = run(&Checks {
+            http: Some(url),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(results.http, Some(Outcome::Ok));
+        assert_eq!(results.to_json(), b"{\"http\":\"ok\"}");
+
+        let url = serve("HTTP/1.1 302 Found\r\nLocation: http://portal/\r\n\r\n").await;
+        let results = run(&Checks {
+            tcp: Some(url[7..].split('/').next().unwrap().to_string()),
+            http: Some(url),
+            ..Default::default()
+        })
+        .await;
+        assert_eq!(results.tcp, Some(Outcome::Ok));
+        assert_eq!(results.http, Some(Outcome::Portal));
+        assert_eq!(results.to_json(), b"{\"tcp\":\"ok\",\"http\":\"portal\"}");
+    }
+}
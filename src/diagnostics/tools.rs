@@ -0,0 +1,161 @@
+use crate::backend::LinkStats;
+use crate::connect::ip_info::{ip_info, json_opt};
+use log::info;
+use std::time::Duration;
+
+const PING_COUNT: &str = "3";
+const LOOKUP_TIMEOUT: Duration = Duration::from_secs(5);
+pub(crate) const NAME_MAX_LENGTH: usize = 253;
+
+// Parses the summary of iputils and busybox ping, e.g.
+// "3 packets transmitted, 3 received, 0% packet loss, time 2003ms"
+// "rtt min/avg/max/mdev = 1.910/2.230/2.533/0.254 ms"
+// and returns transmitted, received and average round trip time in ms.
+fn parse_ping(output: &str) -> Option<(u32, u32, Option<String>)> {
+    let summary = output
+        .lines()
+        .find(|line| line.contains("packets transmitted"))?;
+    let mut numbers = summary
+        .split(',')
+        .filter_map(|part| part.split_whitespace().next()?.parse::<u32>().ok());
+    let transmitted = numbers.next()?;
+    let received = numbers.next()?;
+    let rtt = output
+        .lines()
+        .find(|line| line.contains("min/avg/max"))
+        .and_then(|line| line.split('=').nth(1))
+        .and_then(|values| values.trim().split('/').nth(1))
+        .map(|avg| avg.to_string());
+    Some((transmitted, received, rtt))
+}
+
+// JSON object with the ping result, e.g.
+// {"gw":"192.168.0.1","sent":"3","received":"3","rtt":"2.230"}
+pub async fn ping_gateway(interface: &str) -> Result<Vec<u8>, String> {
+    let info = ip_info(interface).await?;
+    let gateway = info
+        .gateways
+        .first()
+        .ok_or(format!("No default gateway on {}", interface))?;
+    // link-local gateways, as common with IPv6, need the interface as scope
+    let target = if gateway.starts_with("fe80:") {
+        format!("{}%{}", gateway, interface)
+    } else {
+        gateway.clone()
+    };
+    info!("Pinging gateway {}", target);
+    let output = tokio::process::Command::new("ping")
+        .args(["-c", PING_COUNT, "-W", "1", &target])
+        .output()
+        .await
+        .map_err(|e| format!("ping failed: {}", e))?;
+    // ping also fails if no reply was received, which is a valid result here
+    let (transmitted, received, rtt) =
+        parse_ping(&String::from_utf8_lossy(&output.stdout)).ok_or(format!(
+            "ping failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ))?;
+    Ok(format!(
+        "{{\"gw\":\"{}\",\"sent\":\"{}\",\"received\":\"{}\",\"rtt\":\"{}\"}}",
+        gateway,
+        transmitted,
+        received,
+        json_opt(&rtt)
+    )
+    .into_bytes())
+}
+
+fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= NAME_MAX_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_')
+}
+
+// JSON object with the resolved addresses, e.g.
+// {"name":"example.com","addresses":["93.184.216.34","2606:2800:220:1:248:1893:25c8:1946"]}
+pub async fn lookup(name: &[u8]) -> Result<Vec<u8>, String> {
+    let name = std::str::from_utf8(name).map_err(|e| e.to_string())?;
+    // this also guarantees that the name needs no escaping in JSON
+    if !is_valid_name(name) {
+        return Err(format!("Invalid host name {:?}", name));
+    }
+    info!("Looking up {}", name);
+    let addresses = tokio::time::timeout(LOOKUP_TIMEOUT, tokio::net::lookup_host((name, 0)))
+        .await
+        .map_err(|_| format!("Lookup of {} timed out", name))?
+        .map_err(|e| e.to_string())?;
+    let mut ips: Vec<String> = vec![];
+    for address in addresses {
+        let ip = format!("\"{}\"", address.ip());
+        if !ips.contains(&ip) {
+            ips.push(ip);
+        }
+    }
+    Ok(format!(
+        "{{\"name\":\"{}\",\"addresses\":[{}]}}",
+        name,
+        ips.join(",")
+    )
+    .into_bytes())
+}
+
+// JSON object with the link statistics, unknown values are empty, e.g.
+// {"rssi":"-52","speed":"72","noise":"","freq":"2412"}
+pub fn link_stats_json(stats: &LinkStats) -> Vec<u8> {
+    format!(
+        "{{\"rssi\":\"{}\",\"speed\":\"{}\",\"noise\":\"{}\",\"freq\":\"{}\"}}",
+        json_opt(&stats.signal),
+        json_opt(&stats.link_speed),
+        json_opt(&stats.noise),
+        json_opt(&stats.frequency)
+    )
+    .into_bytes()
+}
+
+// Compact form of the link statistics that fits into a notification at the
+// default MTU, see the signal characteristic.
+pub fn signal_value(stats: &LinkStats) -> Vec<u8> {
+    let rssi = stats
+        .signal
+        .map(|signal| signal.clamp(i8::MIN as i32, 0) as i8)
+        .unwrap_or_default();
+    let speed = stats
+        .link_speed
+        .map(|speed| speed.min(u16::MAX as u32) as u16)
+        .unwrap_or_default();
+    let mut value = vec![rssi as u8];
+    value.extend(speed.to_le_bytes());
+    value
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_ping() {
+        let iputils = "PING 192.168.0.1 (192.168.0.1) 56(84) bytes of data.\n\
+                       64 bytes from 192.168.0.1: icmp_seq=1 ttl=64 time=2.53 ms\n\
+                       \n\
+                       --- 192.168.0.1 ping statistics ---\n\
+                       3 packets transmitted, 3 received, 0% packet loss, time 2003ms\n\
+                       rtt min/avg/max/mdev = 1.910/2.230/2.533/0.254 ms\n";
+        assert_eq!(parse_ping(iputils), Some((3, 3, Some("2.230".to_string()))));
+        let busybox = "--- 192.168.0.1 ping statistics ---\n\
+                       3 packets transmitted, 0 packets received, 100% packet loss\n";
+        assert_eq!(parse_ping(busybox), Some((3, 0, None)));
+        assert_eq!(parse_ping("ping: sendto: Network unreachable\n"), None);
+    }
+
+    #[tokio::test]
+    async fn test_lookup() {
+        // depending on /etc/hosts, localhost may resolve to ::1 as well
+        let result = String::from_utf8(lookup(b"localhost").await.unwrap()).unwrap();
+        assert!(result.starts_with("{\"name\":\"localhost\",\"addresses\":["));
+        assert!(result.contains("\"127.0.0.1\"") || result.contains("\"::1\""));
+        assert!(lookup(b"\"}{").await.is_err());
+        assert!(lookup(b"").await.is_err());
+    }
+}
@@ -0,0 +1,547 @@
+use crate::authorize;
+use crate::network_config::{IpConfig, Method, NetworkConfig};
+use crate::notify::Notifiers;
+use crate::request::Request;
+use bluer::gatt::local::{
+    characteristic_control, service_control, Characteristic, CharacteristicNotifier,
+    CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+    CharacteristicWriteMethod, ReqError, ReqResult, Service,
+};
+use enclose::enclose;
+use futures::FutureExt;
+use log::{debug, error, info};
+use std::convert::TryFrom;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+
+pub const IP_CONFIG_SERVICE_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0xd69a37ee1d8a4329bd2425db4af3c866);
+const STATE_IP_CONFIG_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa7);
+const METHOD_IP_CONFIG_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa8);
+const ADDRESSES_IP_CONFIG_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa9);
+const GATEWAYS_IP_CONFIG_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faaa);
+const DNS_IP_CONFIG_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faab);
+const LIST_MAX_LENGTH: usize = 255;
+
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum IpConfigState {
+    Idle = 0u8,
+    Apply = 1u8,
+    Applied = 2u8,
+    Failed = 3u8,
+}
+
+impl std::convert::TryFrom<u8> for IpConfigState {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let result = match value {
+            0u8 => IpConfigState::Idle,
+            1u8 => IpConfigState::Apply,
+            2u8 => IpConfigState::Applied,
+            3u8 => IpConfigState::Failed,
+            _ => Err(format!("invalid ip config state: {}", value))?,
+        };
+
+        Ok(result)
+    }
+}
+
+impl std::convert::TryFrom<u8> for Method {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let result = match value {
+            0u8 => Method::Dhcp,
+            1u8 => Method::Static,
+            _ => Err(format!("invalid ip config method: {}", value))?,
+        };
+
+        Ok(result)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum List {
+    Addresses,
+    Gateways,
+    Dns,
+}
+
+struct IpConfigSharedData {
+    // IP config state, u8
+    // 0: Idle
+    // 1: Apply
+    // 2: Applied
+    // 3: Apply failed
+    // Client is expected to write an 1 after setting the other characteristics to apply them.
+    // Applying may take a while, so the write request returns right away and the server
+    // sets this value to 2 or 3 and notifies it when applying finished.
+    state_ip_config_value: Mutex<Vec<u8>>,
+    // Notifier instances for state_ip_config_value
+    state_ip_config_notifiers: Mutex<Notifiers>,
+    apply_task: Mutex<Option<JoinHandle<()>>>,
+    // IP config method, u8
+    // 0: DHCP
+    // 1: static
+    method_ip_config_value: Mutex<Vec<u8>>,
+    // The following are whitespace or comma separated UTF-8 lists.
+    // Addresses in CIDR notation, e.g. "192.168.0.2/24 fd00::2/64", only used for static config
+    addresses_ip_config_value: Mutex<Vec<u8>>,
+    // Gateways, at most one per address family, only used for static config
+    gateways_ip_config_value: Mutex<Vec<u8>>,
+    // DNS servers
+    dns_ip_config_value: Mutex<Vec<u8>>,
+    authorized: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    network_config: Arc<dyn NetworkConfig + Send + Sync>,
+}
+
+impl IpConfigSharedData {
+    fn new(
+        network_config: Arc<dyn NetworkConfig + Send + Sync>,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> IpConfigSharedData {
+        IpConfigSharedData {
+            state_ip_config_value: Mutex::new(vec![IpConfigState::Idle as u8]),
+            state_ip_config_notifiers: Mutex::new(Notifiers::default()),
+            apply_task: Mutex::new(Option::None),
+            method_ip_config_value: Mutex::new(vec![Method::Dhcp as u8]),
+            addresses_ip_config_value: Mutex::new(vec![]),
+            gateways_ip_config_value: Mutex::new(vec![]),
+            dns_ip_config_value: Mutex::new(vec![]),
+            authorized: auth,
+            network_config,
+        }
+    }
+
+    fn list(&self, list: List) -> &Mutex<Vec<u8>> {
+        match list {
+            List::Addresses => &self.addresses_ip_config_value,
+            List::Gateways => &self.gateways_ip_config_value,
+            List::Dns => &self.dns_ip_config_value,
+        }
+    }
+}
+
+async fn read_state(shared: Arc<IpConfigSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config state read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let state_ip_config_value = shared.state_ip_config_value.lock().await.clone();
+    info!("IP config state read request {:?}", &req);
+    debug!(" with value {:x?}", &state_ip_config_value);
+    Ok(state_ip_config_value)
+}
+
+async fn write_state(
+    shared: Arc<IpConfigSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config state write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("IP config state write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    if new_value.len() != 1 {
+        error!("IP config state write invalid length.");
+        return Err(ReqError::InvalidValueLength);
+    }
+    let mut state_ip_config_value = shared.state_ip_config_value.lock().await;
+    match IpConfigState::try_from(new_value[0]) {
+        Ok(IpConfigState::Idle) => {
+            // a running apply isn't cancelled, its result is just not reported
+            state_ip_config_value[0] = IpConfigState::Idle as u8;
+        }
+        Ok(IpConfigState::Apply) => {
+            if state_ip_config_value[0] == IpConfigState::Apply as u8 {
+                error!("IP config is already being applied.");
+                return Err(ReqError::NotSupported);
+            }
+            // the method value is validated on write
+            let method = Method::try_from(shared.method_ip_config_value.lock().await[0]).unwrap();
+            let config = IpConfig::parse(
+                method,
+                &shared.addresses_ip_config_value.lock().await,
+                &shared.gateways_ip_config_value.lock().await,
+                &shared.dns_ip_config_value.lock().await,
+            );
+            match config {
+                Err(e) => {
+                    error!("IP config invalid: {:?}", e);
+                    state_ip_config_value[0] = IpConfigState::Failed as u8;
+                    return Err(ReqError::Failed);
+                }
+                Ok(config) => {
+                    // Applying restarts services, so don't block the request
+                    state_ip_config_value[0] = IpConfigState::Apply as u8;
+                    *shared.apply_task.lock().await =
+                        Some(tokio::spawn(run_apply(shared.clone(), config)));
+                }
+            }
+        }
+        _ => {
+            error!("IP config state write invalid state, expected either 0 or 1.");
+            return Err(ReqError::NotSupported);
+        }
+    }
+    Ok(())
+}
+
+async fn run_apply(shared: Arc<IpConfigSharedData>, config: IpConfig) {
+    info!("Applying ip config {:?}", &config);
+    let result = shared.network_config.apply(&config).await;
+    let mut state_ip_config_value = shared.state_ip_config_value.lock().await;
+    // the client may have reset the state meanwhile
+    if state_ip_config_value[0] != IpConfigState::Apply as u8 {
+        info!("IP config state was reset, not reporting the result");
+        return;
+    }
+    match result {
+        Err(e) => {
+            error!("Applying ip config failed: {:?}", e);
+            state_ip_config_value[0] = IpConfigState::Failed as u8;
+        }
+        Ok(_o) => {
+            info!("Applying ip config successful");
+            state_ip_config_value[0] = IpConfigState::Applied as u8;
+        }
+    }
+    let mut notifiers = shared.state_ip_config_notifiers.lock().await;
+    if notifiers.is_active() {
+        info!(
+            "Notifying ip config state with value {:x?}",
+            &state_ip_config_value
+        );
+        notifiers.notify(&state_ip_config_value).await;
+    }
+}
+
+async fn start_notify_state(shared: Arc<IpConfigSharedData>, notifier: CharacteristicNotifier) {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config state notify no auth");
+        return;
+    }
+    info!(
+        "IP config state accepting notify, confirming {}",
+        notifier.confirming()
+    );
+    shared.state_ip_config_notifiers.lock().await.add(notifier);
+}
+
+async fn read_method(shared: Arc<IpConfigSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config method read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let method_ip_config_value = shared.method_ip_config_value.lock().await.clone();
+    info!("IP config method read request {:?}", &req);
+    debug!(" with value {:x?}", &method_ip_config_value);
+    Ok(method_ip_config_value)
+}
+
+async fn write_method(
+    shared: Arc<IpConfigSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config method write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("IP config method write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    if new_value.len() != 1 {
+        error!("IP config method write invalid length.");
+        return Err(ReqError::InvalidValueLength);
+    }
+    if let Err(e) = Method::try_from(new_value[0]) {
+        error!("IP config method write failed: {}", e);
+        return Err(ReqError::NotSupported);
+    }
+    *shared.method_ip_config_value.lock().await = new_value;
+    Ok(())
+}
+
+async fn read_list(
+    shared: Arc<IpConfigSharedData>,
+    list: List,
+    req: impl Request,
+) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config {:?} read no auth {:?}", list, &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let value = shared.list(list).lock().await.clone();
+    info!("IP config {:?} read request {:?}", list, &req);
+    debug!(" with value {:x?}", &value);
+    let offset = req.offset() as usize;
+    let mtu = req.mtu() as usize;
+    if offset > value.len() {
+        error!("IP config {:?} returning invalid offset", list);
+        return Err(ReqError::InvalidOffset);
+    }
+    let mut size = value.len() - offset;
+    if size > mtu {
+        size = mtu;
+    }
+    Ok(value[offset..(offset + size)].to_vec())
+}
+
+async fn write_list(
+    shared: Arc<IpConfigSharedData>,
+    list: List,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("IP config {:?} write no auth {:?}", list, &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("IP config {:?} write request {:?}", list, &req);
+    debug!(" with value {:x?}", &new_value);
+    let offset = req.offset() as usize;
+    let len = new_value.len();
+    if len + offset > LIST_MAX_LENGTH {
+        error!("IP config {:?} write invalid length.", list);
+        return Err(ReqError::InvalidValueLength);
+    }
+    let mut value = shared.list(list).lock().await;
+    // like the SSID, lists are variable length and cleared by a write at offset 0
+    if offset == 0 {
+        value.clear();
+    }
+    if offset > value.len() {
+        error!("IP config {:?} write invalid offset.", list);
+        return Err(ReqError::InvalidOffset);
+    }
+    let endoffset = (offset + len).min(value.len());
+    value.splice(offset..endoffset, new_value.iter().cloned());
+    Ok(())
+}
+
+use authorize::Authorized;
+
+pub struct IpConfigService {
+    shared: Arc<IpConfigSharedData>,
+}
+
+impl IpConfigService {
+    pub fn new(
+        network_config: Arc<dyn NetworkConfig + Send + Sync>,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> IpConfigService {
+        IpConfigService {
+            shared: Arc::new(IpConfigSharedData::new(network_config, auth)),
+        }
+    }
+    fn list_characteristic(&self, uuid: uuid::Uuid, list: List) -> Characteristic {
+        let shared = self.shared.clone();
+        let (_list_char_control, list_char_handle) = characteristic_control();
+        Characteristic {
+            uuid,
+            read: Some(CharacteristicRead {
+                read: true,
+                fun: Box::new(
+                    enclose!( (shared) move |req| read_list(shared.clone(), list, req).boxed()),
+                ),
+                ..Default::default()
+            }),
+            write: Some(CharacteristicWrite {
+                write: true,
+                method: CharacteristicWriteMethod::Fun(Box::new(
+                    enclose!( (shared) move |new_value, req| {
+                        let shared = shared.clone();
+                        write_list(shared, list, new_value, req).boxed()
+                    }),
+                )),
+                ..Default::default()
+            }),
+            control_handle: list_char_handle,
+            ..Default::default()
+        }
+    }
+    pub fn service_entry(&mut self) -> Service {
+        let shared = self.shared.clone();
+        let (_ip_config_service_control, ip_config_service_handle) = service_control();
+        let (_state_ip_config_char_control, state_ip_config_char_handle) = characteristic_control();
+        let (_method_ip_config_char_control, method_ip_config_char_handle) =
+            characteristic_control();
+        Service {
+            uuid: IP_CONFIG_SERVICE_UUID,
+            primary: true,
+            characteristics: vec![
+                Characteristic {
+                    uuid: STATE_IP_CONFIG_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_state(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_state(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(
+                            enclose!( (shared) move |notifier| {
+                                let shared = shared.clone();
+                                start_notify_state(shared, notifier).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: state_ip_config_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: METHOD_IP_CONFIG_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_method(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_method(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: method_ip_config_char_handle,
+                    ..Default::default()
+                },
+                self.list_characteristic(ADDRESSES_IP_CONFIG_CHAR_UUID, List::Addresses),
+                self.list_characteristic(GATEWAYS_IP_CONFIG_CHAR_UUID, List::Gateways),
+                self.list_characteristic(DNS_IP_CONFIG_CHAR_UUID, List::Dns),
+            ],
+            control_handle: ip_config_service_handle,
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize::MockAuthorized;
+    use crate::request::TestRequest;
+    use async_trait::async_trait;
+
+    #[derive(Default)]
+    struct MockNetworkConfig {
+        applied: std::sync::Mutex<Vec<IpConfig>>,
+    }
+
+    #[async_trait]
+    impl NetworkConfig for MockNetworkConfig {
+        async fn apply(&self, config: &IpConfig) -> Result<(), String> {
+            self.applied.lock().unwrap().push(config.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply() {
+        let network_config = Arc::new(MockNetworkConfig::default());
+        let service = IpConfigService::new(
+            network_config.clone(),
+            Arc::new(Mutex::new(MockAuthorized(true))),
+        );
+        let shared = service.shared.clone();
+        write_method(shared.clone(), vec![1], TestRequest::default())
+            .await
+            .unwrap();
+        write_list(
+            shared.clone(),
+            List::Addresses,
+            b"192.168.0.2/".to_vec(),
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        let req = TestRequest {
+            offset: 12,
+            ..Default::default()
+        };
+        write_list(shared.clone(), List::Addresses, b"24".to_vec(), req)
+            .await
+            .unwrap();
+        write_list(
+            shared.clone(),
+            List::Gateways,
+            b"192.168.0.1".to_vec(),
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        write_state(shared.clone(), vec![1], TestRequest::default())
+            .await
+            .unwrap();
+        assert_eq!(
+            read_state(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![IpConfigState::Apply as u8]
+        );
+        // applying is in progress
+        assert!(matches!(
+            write_state(shared.clone(), vec![1], TestRequest::default()).await,
+            Err(ReqError::NotSupported)
+        ));
+        let task = shared.apply_task.lock().await.take().unwrap();
+        task.await.unwrap();
+        assert_eq!(
+            read_state(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![IpConfigState::Applied as u8]
+        );
+        assert_eq!(
+            *network_config.applied.lock().unwrap(),
+            vec![IpConfig::parse(Method::Static, b"192.168.0.2/24", b"192.168.0.1", b"").unwrap()]
+        );
+
+        // invalid configuration
+        write_list(
+            shared.clone(),
+            List::Addresses,
+            b"192.168.0.2".to_vec(),
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        assert!(matches!(
+            write_state(shared.clone(), vec![1], TestRequest::default()).await,
+            Err(ReqError::Failed)
+        ));
+        assert_eq!(
+            read_state(shared, TestRequest::default()).await.unwrap(),
+            vec![IpConfigState::Failed as u8]
+        );
+        assert_eq!(network_config.applied.lock().unwrap().len(), 1);
+    }
+}
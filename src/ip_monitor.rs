@@ -0,0 +1,232 @@
+// Tracks the IP connectivity of the wireless interface from rtnetlink
+// address and route events. The wifi daemons only report the IPv4 address
+// leased by their own DHCP client, if at all, so they can't tell when an
+// IPv6-only or statically configured network is usable.
+
+use futures::{StreamExt, TryStreamExt};
+use log::{debug, error, info};
+use netlink_packet_route::{address, constants::*, route, AddressMessage, RouteMessage};
+use netlink_sys::{AsyncSocket, SocketAddr};
+use rtnetlink::{Handle, IpVersion};
+use tokio::sync::watch;
+
+/// When a connection counts as established.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Ready {
+    // association and authentication with the AP completed
+    Link,
+    Ipv4,
+    Ipv6,
+    // a global IPv4 or IPv6 address, without requiring a default route,
+    // like the daemon reported address formerly
+    Any,
+}
+
+/// Per address family, whether the interface has a usable global address
+/// and a default route.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IpState {
+    pub ipv4: bool,
+    pub ipv6: bool,
+    // a global IPv4 or IPv6 address, regardless of routes
+    pub address: bool,
+}
+
+impl IpState {
+    pub fn is_ready(&self, ready: Ready) -> bool {
+        match ready {
+            Ready::Link => true,
+            Ready::Ipv4 => self.ipv4,
+            Ready::Ipv6 => self.ipv6,
+            Ready::Any => self.address,
+        }
+    }
+}
+
+const fn nl_mgrp(group: u32) -> u32 {
+    1 << (group - 1)
+}
+
+// IPv4 link-local addresses (169.254/16) are assigned when DHCP failed,
+// not always with link scope.
+fn is_ipv4_link_local(address: &AddressMessage) -> bool {
+    address.header.family as u16 == AF_INET
+        && address.nlas.iter().any(|nla| {
+            matches!(nla, address::Nla::Local(ip) | address::Nla::Address(ip)
+                if ip.starts_with(&[169, 254]))
+        })
+}
+
+fn has_address(addresses: &[AddressMessage], family: u16) -> bool {
+    addresses.iter().any(|address| {
+        address.header.family as u16 == family
+            && address.header.scope == RT_SCOPE_UNIVERSE
+            && !is_ipv4_link_local(address)
+            // IPv6 addresses are unusable until duplicate address detection passed
+            && address.header.flags as u32 & (IFA_F_TENTATIVE | IFA_F_DADFAILED) == 0
+    })
+}
+
+fn has_default_route(routes: &[RouteMessage], index: u32) -> bool {
+    routes.iter().any(|r| {
+        r.header.destination_prefix_length == 0
+            && r.header.table == RT_TABLE_MAIN
+            && r.header.kind == RTN_UNICAST
+            && r.nlas
+                .iter()
+                .any(|nla| matches!(nla, route::Nla::Oif(oif) if *oif == index))
+    })
+}
+
+async fn ip_state(handle: &Handle, interface: &str) -> Result<IpState, rtnetlink::Error> {
+    let link = handle
+        .link()
+        .get()
+        .match_name(interface.to_string())
+        .execute()
+        .try_next()
+        .await?;
+    let index = match link {
+        Some(link) => link.header.index,
+        None => return Ok(IpState::default()),
+    };
+    let addresses: Vec<AddressMessage> = handle
+        .address()
+        .get()
+        .set_link_index_filter(index)
+        .execute()
+        .try_collect()
+        .await?;
+    let ipv4_routes: Vec<RouteMessage> = handle
+        .route()
+        .get(IpVersion::V4)
+        .execute()
+        .try_collect()
+        .await?;
+    let ipv6_routes: Vec<RouteMessage> = handle
+        .route()
+        .get(IpVersion::V6)
+        .execute()
+        .try_collect()
+        .await?;
+    Ok(IpState {
+        ipv4: has_address(&addresses, AF_INET) && has_default_route(&ipv4_routes, index),
+        ipv6: has_address(&addresses, AF_INET6) && has_default_route(&ipv6_routes, index),
+        address: has_address(&addresses, AF_INET) || has_address(&addresses, AF_INET6),
+    })
+}
+
+/// Starts monitoring the interface, the returned receiver always holds the
+/// current state.
+pub fn spawn(interface: String) -> std::io::Result<watch::Receiver<IpState>> {
+    let (mut connection, handle, mut messages) = rtnetlink::new_connection()?;
+    let groups = nl_mgrp(RTNLGRP_LINK)
+        | nl_mgrp(RTNLGRP_IPV4_IFADDR)
+        | nl_mgrp(RTNLGRP_IPV6_IFADDR)
+        | nl_mgrp(RTNLGRP_IPV4_ROUTE)
+        | nl_mgrp(RTNLGRP_IPV6_ROUTE);
+    connection
+        .socket_mut()
+        .socket_mut()
+        .bind(&SocketAddr::new(0, groups))?;
+    tokio::spawn(connection);
+
+    let (sender, receiver) = watch::channel(IpState::default());
+    tokio::spawn(async move {
+        loop {
+            // Events are only used as trigger, the state is always read
+            // completely, so it can't drift from the kernel's.
+            match ip_state(&handle, &interface).await {
+                Ok(state) => {
+                    sender.send_if_modified(|current| {
+                        if *current == state {
+                            return false;
+                        }
+                        info!("IP state of {} changed to {:?}", &interface, state);
+                        *current = state;
+                        true
+                    });
+                }
+                // e.g. the interface doesn't exist (yet)
+                Err(e) => debug!("Getting IP state of {} failed: {}", &interface, e),
+            }
+            if messages.next().await.is_none() {
+                error!("Lost rtnetlink connection");
+                return;
+            }
+            // coalesce bursts of events, e.g. on DHCP lease
+            while let Ok(Some(_)) = messages.try_next() {}
+        }
+    });
+    Ok(receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn address(family: u16, scope: u8, flags: u32) -> AddressMessage {
+        let mut address = AddressMessage::default();
+        address.header.family = family as u8;
+        address.header.scope = scope;
+        address.header.flags = flags as u8;
+        address
+    }
+
+    fn default_route(oif: u32) -> RouteMessage {
+        let mut route = RouteMessage::default();
+        route.header.table = RT_TABLE_MAIN;
+        route.header.kind = RTN_UNICAST;
+        route.nlas.push(route::Nla::Oif(oif));
+        route
+    }
+
+    #[test]
+    fn test_ip_state() {
+        assert!(has_address(
+            &[address(AF_INET, RT_SCOPE_UNIVERSE, 0)],
+            AF_INET
+        ));
+        assert!(!has_address(
+            &[address(AF_INET6, RT_SCOPE_LINK, 0)],
+            AF_INET6
+        ));
+        assert!(!has_address(
+            &[address(AF_INET6, RT_SCOPE_UNIVERSE, IFA_F_TENTATIVE)],
+            AF_INET6
+        ));
+        assert!(has_default_route(&[default_route(3)], 3));
+        assert!(!has_default_route(&[default_route(2)], 3));
+        let mut route = default_route(3);
+        route.header.destination_prefix_length = 24;
+        assert!(!has_default_route(&[route], 3));
+
+        // DHCP failed, a link-local address doesn't count, whatever its scope
+        assert!(!has_address(&[address(AF_INET, RT_SCOPE_LINK, 0)], AF_INET));
+        let mut link_local = address(AF_INET, RT_SCOPE_UNIVERSE, 0);
+        link_local
+            .nlas
+            .push(address::Nla::Local(vec![169, 254, 12, 34]));
+        assert!(!has_address(&[link_local], AF_INET));
+        let mut leased = address(AF_INET, RT_SCOPE_UNIVERSE, 0);
+        leased.nlas.push(address::Nla::Local(vec![192, 168, 0, 2]));
+        assert!(has_address(&[leased], AF_INET));
+
+        let ipv6_only = IpState {
+            ipv4: false,
+            ipv6: true,
+            address: true,
+        };
+        assert!(ipv6_only.is_ready(Ready::Any));
+        assert!(ipv6_only.is_ready(Ready::Ipv6));
+        assert!(!ipv6_only.is_ready(Ready::Ipv4));
+        assert!(IpState::default().is_ready(Ready::Link));
+        // no default route, e.g. a LAN without gateway
+        let unrouted = IpState {
+            address: true,
+            ..Default::default()
+        };
+        assert!(unrouted.is_ready(Ready::Any));
+        assert!(!unrouted.is_ready(Ready::Ipv4));
+    }
+}
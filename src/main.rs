@@ -1,17 +1,68 @@
 pub mod authorize;
+pub mod backend;
 pub mod connect;
+pub mod device_info;
+pub mod diagnostics;
+pub mod ip_config;
+pub mod ip_monitor;
+pub mod network_config;
+pub mod notify;
+pub mod request;
 pub mod scan;
 
 use authorize::AuthorizeService;
+use backend::{
+    iwd::Iwd,
+    network_manager::NetworkManager,
+    wpa_supplicant::{parse_ctrl_dir, CtrlInterface, WpaSupplicant},
+    Persistence, WifiBackend,
+};
 use bluer::{adv::Advertisement, gatt::local::Application};
-use clap::Parser;
+use clap::{CommandFactory, Parser, ValueEnum};
 use connect::ConnectService;
+use device_info::{DeviceInfo, DeviceInfoService};
+use diagnostics::{reachability::Checks, DiagnosticsService};
+use ip_config::IpConfigService;
+use ip_monitor::Ready;
 use log::{debug, info};
+use network_config::{
+    ifupdown::Ifupdown, network_manager::NetworkManager as NmNetworkConfig, networkd::Networkd,
+    NetworkConfig,
+};
 use scan::ScanService;
-use std::{collections::BTreeMap, env, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, env, path::PathBuf, sync::Arc, time::Duration};
 use tokio::sync::Mutex;
 use tokio::time::interval;
 
+#[derive(Clone, Copy, ValueEnum)]
+enum Backend {
+    WpaSupplicant,
+    NetworkManager,
+    Iwd,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum PersistenceMode {
+    SaveConfig,
+    Memory,
+    DropIn,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum ReadyMode {
+    L2,
+    Ipv4,
+    Ipv6,
+    Any,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum NetworkConfigBackend {
+    Networkd,
+    NetworkManager,
+    Ifupdown,
+}
+
 #[derive(Parser)]
 #[clap(version, author)]
 struct Opts {
@@ -22,6 +73,73 @@ struct Opts {
     /// secret shared between client and server used for BLE communication
     #[clap(short, long)]
     ble_secret: String,
+
+    /// service managing the wireless network interface
+    #[clap(long, value_enum, default_value = "wpa-supplicant")]
+    backend: Backend,
+
+    /// wpa_supplicant control interface directory, either a path or "DIR=<path> GROUP=<group>"
+    #[clap(long, default_value = "/var/run/wpa_supplicant")]
+    ctrl_dir: String,
+
+    /// wpa_supplicant global control interface socket, used instead of the socket in ctrl-dir
+    #[clap(long)]
+    ctrl_global: Option<PathBuf>,
+
+    /// how the configured network is persisted
+    #[clap(long, value_enum, default_value = "save-config")]
+    persistence: PersistenceMode,
+
+    /// configuration file written in drop-in persistence mode
+    #[clap(
+        long,
+        default_value = "/etc/wpa_supplicant/wifi-commissioning-gatt.conf"
+    )]
+    drop_in_file: PathBuf,
+
+    /// when a connection is reported as established: wifi link up, additionally a global address and default route of the given IP version, or any global address
+    #[clap(long, value_enum, default_value = "any")]
+    ready: ReadyMode,
+
+    /// host name resolved to check reachability after connect
+    #[clap(long)]
+    check_dns: Option<String>,
+
+    /// host:port connected to via TCP to check reachability after connect
+    #[clap(long)]
+    check_tcp: Option<String>,
+
+    /// http URL expected to return 204 to check reachability and detect captive portals after connect
+    #[clap(long)]
+    check_http: Option<String>,
+
+    /// maximum number of networks reported by a scan, networks are grouped by SSID and the strongest are kept
+    #[clap(long)]
+    scan_max_results: Option<usize>,
+
+    /// interval in seconds of background scans while not connected, scans are answered from their cached results
+    #[clap(long)]
+    scan_interval: Option<u64>,
+
+    /// service applying the IP configuration, enables the IP configuration GATT service
+    #[clap(long, value_enum)]
+    network_config: Option<NetworkConfigBackend>,
+
+    /// manufacturer name offered by the device information service
+    #[clap(long)]
+    manufacturer: Option<String>,
+
+    /// model number offered by the device information service
+    #[clap(long)]
+    model: Option<String>,
+
+    /// serial number offered by the device information service, readable without authorization, so it must not be the BLE secret
+    #[clap(long)]
+    serial_number: Option<String>,
+
+    /// file the firmware revision is read from, os-release style (VERSION_ID) or a plain version
+    #[clap(long, default_value = "/etc/os-release")]
+    firmware_file: PathBuf,
 }
 
 static DEFAULT_SCAN_SERVICE_BEACON: &str = "omnectWifiConfig";
@@ -51,6 +169,31 @@ async fn main() -> bluer::Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     let opts: Opts = Opts::parse();
+    // iwd always keeps the provisioned networks in its storage directory
+    if matches!(opts.backend, Backend::Iwd)
+        && !matches!(opts.persistence, PersistenceMode::SaveConfig)
+    {
+        Opts::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "the iwd backend only supports the save-config persistence",
+            )
+            .exit();
+    }
+    // the profile configured by the network-manager network config is only created by
+    // the network-manager backend
+    if matches!(
+        opts.network_config,
+        Some(NetworkConfigBackend::NetworkManager)
+    ) && !matches!(opts.backend, Backend::NetworkManager)
+    {
+        Opts::command()
+            .error(
+                clap::error::ErrorKind::ArgumentConflict,
+                "the network-manager network config requires the network-manager backend",
+            )
+            .exit();
+    }
 
     let adapter: bluer::Adapter;
     let adapter_name: String;
@@ -98,17 +241,91 @@ async fn main() -> bluer::Result<()> {
         &adapter_name
     );
 
+    let persistence = match opts.persistence {
+        PersistenceMode::SaveConfig => Persistence::SaveConfig,
+        PersistenceMode::Memory => Persistence::Memory,
+        PersistenceMode::DropIn => Persistence::DropIn(opts.drop_in_file.clone()),
+    };
+    let backend: Arc<dyn WifiBackend + Send + Sync> = match opts.backend {
+        Backend::WpaSupplicant => {
+            let ctrl = match &opts.ctrl_global {
+                Some(path) => CtrlInterface::Global(path.clone()),
+                None => CtrlInterface::Directory(parse_ctrl_dir(&opts.ctrl_dir)),
+            };
+            Arc::new(WpaSupplicant::new(
+                opts.interface.clone(),
+                ctrl,
+                persistence,
+            ))
+        }
+        Backend::NetworkManager => {
+            Arc::new(NetworkManager::new(opts.interface.clone(), persistence)?)
+        }
+        Backend::Iwd => Arc::new(Iwd::new(opts.interface.clone())?),
+    };
+
+    let ready = match opts.ready {
+        ReadyMode::L2 => Ready::Link,
+        ReadyMode::Ipv4 => Ready::Ipv4,
+        ReadyMode::Ipv6 => Ready::Ipv6,
+        ReadyMode::Any => Ready::Any,
+    };
+    let ip_state = ip_monitor::spawn(opts.interface.clone())?;
+
     let authorize_service = Arc::new(Mutex::new(AuthorizeService::new(opts.ble_secret.clone())));
-    let mut scan_service = ScanService::new(opts.interface.clone(), authorize_service.clone());
-    let mut connect_service =
-        ConnectService::new(opts.interface.clone(), authorize_service.clone());
+    let mut scan_service = ScanService::new(
+        backend.clone(),
+        opts.scan_max_results,
+        opts.scan_interval.map(Duration::from_secs),
+        authorize_service.clone(),
+    );
+    let mut connect_service = ConnectService::new(
+        backend.clone(),
+        opts.interface.clone(),
+        ip_state,
+        ready,
+        authorize_service.clone(),
+    );
+
+    let mut diagnostics_service = DiagnosticsService::new(
+        backend.clone(),
+        opts.interface.clone(),
+        Checks {
+            dns: opts.check_dns.clone(),
+            tcp: opts.check_tcp.clone(),
+            http: opts.check_http.clone(),
+        },
+        authorize_service.clone(),
+    );
+
+    let mut device_info_service = DeviceInfoService::new(DeviceInfo {
+        manufacturer: opts.manufacturer.clone(),
+        model: opts.model.clone(),
+        serial_number: opts.serial_number.clone(),
+        firmware_revision: device_info::firmware_revision(&opts.firmware_file),
+    });
+
+    let mut services = vec![
+        scan_service.service_entry(),
+        connect_service.service_entry(),
+        authorize_service.clone().lock().await.service_entry(),
+        diagnostics_service.service_entry(),
+        device_info_service.service_entry(),
+    ];
+    if let Some(network_config) = opts.network_config {
+        let network_config: Arc<dyn NetworkConfig + Send + Sync> = match network_config {
+            NetworkConfigBackend::Networkd => Arc::new(Networkd::new(opts.interface.clone())),
+            NetworkConfigBackend::NetworkManager => {
+                Arc::new(NmNetworkConfig::new(opts.interface.clone()))
+            }
+            NetworkConfigBackend::Ifupdown => Arc::new(Ifupdown::new(opts.interface.clone())),
+        };
+        let mut ip_config_service = IpConfigService::new(network_config, authorize_service.clone());
+        services.push(ip_config_service.service_entry());
+    }
 
     let app = Application {
-        services: vec![
-            scan_service.service_entry(),
-            connect_service.service_entry(),
-            authorize_service.clone().lock().await.service_entry(),
-        ],
+        services,
         _non_exhaustive: (),
     };
     let _app_handle = adapter.serve_gatt_application(app).await?;
@@ -123,6 +340,9 @@ async fn main() -> bluer::Result<()> {
     loop {
         interval.tick().await; // blocks for 1s
         connect_service.tick().await;
+        let connected = connect_service.is_connected().await;
+        scan_service.tick(connected).await;
+        diagnostics_service.tick(connected).await;
         authorize_service.clone().lock().await.tick().await;
     }
 }
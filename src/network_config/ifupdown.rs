@@ -0,0 +1,114 @@
+use super::{run, IpConfig, Method, NetworkConfig};
+use async_trait::async_trait;
+use log::{info, warn};
+use std::fmt::Write;
+use std::path::PathBuf;
+
+const INTERFACES_DIR: &str = "/etc/network/interfaces.d";
+
+fn render(interface: &str, config: &IpConfig) -> String {
+    let mut interfaces = String::new();
+    writeln!(
+        &mut interfaces,
+        "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+         allow-hotplug {}",
+        interface
+    )
+    .unwrap();
+    let dns: Vec<String> = config.dns.iter().map(|dns| dns.to_string()).collect();
+    match config.method {
+        Method::Dhcp => {
+            writeln!(&mut interfaces, "iface {} inet dhcp", interface).unwrap();
+            if !dns.is_empty() {
+                writeln!(&mut interfaces, "    dns-nameservers {}", dns.join(" ")).unwrap();
+            }
+            writeln!(&mut interfaces, "iface {} inet6 auto", interface).unwrap();
+        }
+        Method::Static => {
+            // ifupdown only supports one address per stanza; the DNS servers go
+            // into the first stanza, which is an inet6 one if there are no IPv4 addresses
+            let has_ipv4 = config.addresses.iter().any(|(ip, _)| ip.is_ipv4());
+            for (family, ipv4) in [("inet", true), ("inet6", false)] {
+                let addresses = config
+                    .addresses
+                    .iter()
+                    .filter(|(ip, _)| ip.is_ipv4() == ipv4);
+                for (i, (ip, prefix)) in addresses.enumerate() {
+                    writeln!(&mut interfaces, "iface {} {} static", interface, family).unwrap();
+                    writeln!(&mut interfaces, "    address {}/{}", ip, prefix).unwrap();
+                    if i > 0 {
+                        continue;
+                    }
+                    if let Some(gateway) = config.gateways.iter().find(|gw| gw.is_ipv4() == ipv4) {
+                        writeln!(&mut interfaces, "    gateway {}", gateway).unwrap();
+                    }
+                    if ipv4 == has_ipv4 && !dns.is_empty() {
+                        writeln!(&mut interfaces, "    dns-nameservers {}", dns.join(" ")).unwrap();
+                    }
+                }
+            }
+        }
+    }
+    interfaces
+}
+
+/// ifupdown, configured by a file in /etc/network/interfaces.d. The interface
+/// must not be configured in /etc/network/interfaces itself.
+pub struct Ifupdown {
+    interface: String,
+}
+
+impl Ifupdown {
+    pub fn new(interface: String) -> Ifupdown {
+        Ifupdown { interface }
+    }
+}
+
+#[async_trait]
+impl NetworkConfig for Ifupdown {
+    async fn apply(&self, config: &IpConfig) -> Result<(), String> {
+        let path = PathBuf::from(INTERFACES_DIR)
+            .join(format!("wifi-commissioning-gatt-{}", self.interface));
+        info!("Writing {}", path.display());
+        tokio::fs::write(&path, render(&self.interface, config))
+            .await
+            .map_err(|e| format!("Writing {} failed: {}", path.display(), e))?;
+        if let Err(e) = run("ifdown", &["--force", &self.interface]).await {
+            warn!("{}, but this is ok if the interface was not up before.", e);
+        }
+        run("ifup", &[&self.interface]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let config = IpConfig::parse(
+            Method::Static,
+            b"192.168.0.2/24 fd00::2/64 192.168.1.2/24",
+            b"192.168.0.1",
+            b"1.1.1.1 8.8.8.8",
+        )
+        .unwrap();
+        assert_eq!(
+            render("wlan0", &config),
+            "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+             allow-hotplug wlan0\n\
+             iface wlan0 inet static\n    address 192.168.0.2/24\n    gateway 192.168.0.1\n    dns-nameservers 1.1.1.1 8.8.8.8\n\
+             iface wlan0 inet static\n    address 192.168.1.2/24\n\
+             iface wlan0 inet6 static\n    address fd00::2/64\n"
+        );
+
+        let config =
+            IpConfig::parse(Method::Static, b"fd00::2/64", b"fd00::1", b"fd00::53").unwrap();
+        assert_eq!(
+            render("wlan0", &config),
+            "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+             allow-hotplug wlan0\n\
+             iface wlan0 inet6 static\n    address fd00::2/64\n    gateway fd00::1\n    dns-nameservers fd00::53\n"
+        );
+    }
+}
@@ -0,0 +1,142 @@
+use async_trait::async_trait;
+use std::net::IpAddr;
+
+pub mod ifupdown;
+pub mod network_manager;
+pub mod networkd;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Method {
+    Dhcp,
+    Static,
+}
+
+/// IP configuration of the wireless interface.
+#[derive(Clone, Debug, PartialEq)]
+pub struct IpConfig {
+    pub method: Method,
+    // addresses with prefix length, only used for static configuration
+    pub addresses: Vec<(IpAddr, u8)>,
+    // at most one gateway per address family
+    pub gateways: Vec<IpAddr>,
+    pub dns: Vec<IpAddr>,
+}
+
+fn parse_list(value: &[u8]) -> Result<Vec<&str>, String> {
+    let value = std::str::from_utf8(value).map_err(|e| e.to_string())?;
+    Ok(value
+        .split(|c: char| c.is_whitespace() || c == ',' || c == '\0')
+        .filter(|item| !item.is_empty())
+        .collect())
+}
+
+fn parse_ip(value: &str) -> Result<IpAddr, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid ip address: {}", value))
+}
+
+impl IpConfig {
+    // Parses the whitespace or comma separated lists written by the client.
+    pub fn parse(
+        method: Method,
+        addresses: &[u8],
+        gateways: &[u8],
+        dns: &[u8],
+    ) -> Result<IpConfig, String> {
+        let addresses = parse_list(addresses)?
+            .into_iter()
+            .map(|cidr| {
+                let (ip, prefix) = cidr
+                    .split_once('/')
+                    .ok_or(format!("missing prefix length: {}", cidr))?;
+                let ip = parse_ip(ip)?;
+                let prefix: u8 = prefix
+                    .parse()
+                    .map_err(|_| format!("invalid prefix length: {}", cidr))?;
+                if prefix > if ip.is_ipv4() { 32 } else { 128 } {
+                    return Err(format!("invalid prefix length: {}", cidr));
+                }
+                Ok((ip, prefix))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        let gateways = parse_list(gateways)?
+            .into_iter()
+            .map(parse_ip)
+            .collect::<Result<Vec<_>, String>>()?;
+        let dns = parse_list(dns)?
+            .into_iter()
+            .map(parse_ip)
+            .collect::<Result<Vec<_>, String>>()?;
+
+        if method == Method::Static && addresses.is_empty() {
+            return Err("static configuration without address".to_string());
+        }
+        if gateways.iter().filter(|gw| gw.is_ipv4()).count() > 1
+            || gateways.iter().filter(|gw| gw.is_ipv6()).count() > 1
+        {
+            return Err("more than one gateway per address family".to_string());
+        }
+        Ok(IpConfig {
+            method,
+            addresses,
+            gateways,
+            dns,
+        })
+    }
+}
+
+/// Applies an IP configuration to the wireless interface.
+#[async_trait]
+pub trait NetworkConfig {
+    async fn apply(&self, config: &IpConfig) -> Result<(), String>;
+}
+
+pub(crate) async fn run(program: &str, args: &[&str]) -> Result<(), String> {
+    let output = tokio::process::Command::new(program)
+        .args(args)
+        .output()
+        .await
+        .map_err(|e| format!("{} failed: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!(
+            "{} {} failed: {}",
+            program,
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let config = IpConfig::parse(
+            Method::Static,
+            b"192.168.0.2/24 fd00::2/64",
+            b"192.168.0.1,fd00::1",
+            b"192.168.0.1\n1.1.1.1\0\0",
+        )
+        .unwrap();
+        assert_eq!(
+            config.addresses,
+            vec![
+                ("192.168.0.2".parse().unwrap(), 24),
+                ("fd00::2".parse().unwrap(), 64)
+            ]
+        );
+        assert_eq!(config.gateways.len(), 2);
+        assert_eq!(config.dns.len(), 2);
+
+        assert!(IpConfig::parse(Method::Dhcp, b"", b"", b"").is_ok());
+        assert!(IpConfig::parse(Method::Static, b"", b"", b"").is_err());
+        assert!(IpConfig::parse(Method::Static, b"192.168.0.2", b"", b"").is_err());
+        assert!(IpConfig::parse(Method::Static, b"192.168.0.2/33", b"", b"").is_err());
+        assert!(IpConfig::parse(Method::Static, b"fd00::2/64", b"fd00::1 fd00::3", b"").is_err());
+        assert!(IpConfig::parse(Method::Static, b"192.168.0.2/24", b"", b"dns.google").is_err());
+    }
+}
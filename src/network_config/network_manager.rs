@@ -0,0 +1,101 @@
+use super::{run, IpConfig, Method, NetworkConfig};
+use crate::backend::network_manager::CONNECTION_ID;
+use async_trait::async_trait;
+use log::info;
+use std::net::IpAddr;
+
+fn join<'a>(items: impl Iterator<Item = &'a IpAddr>) -> String {
+    items
+        .map(|ip| ip.to_string())
+        .collect::<Vec<String>>()
+        .join(",")
+}
+
+// nmcli arguments modifying the IP settings of the connection profile
+fn modify_args(config: &IpConfig) -> Vec<String> {
+    let mut args: Vec<String> = vec!["connection", "modify", CONNECTION_ID]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    for (family, is_family) in [
+        ("ipv4", IpAddr::is_ipv4 as fn(&IpAddr) -> bool),
+        ("ipv6", IpAddr::is_ipv6),
+    ] {
+        let addresses: Vec<String> = config
+            .addresses
+            .iter()
+            .filter(|(ip, _)| is_family(ip))
+            .map(|(ip, prefix)| format!("{}/{}", ip, prefix))
+            .collect();
+        let method = match config.method {
+            Method::Static if !addresses.is_empty() => "manual",
+            _ => "auto",
+        };
+        let gateway = join(config.gateways.iter().filter(|ip| is_family(ip)));
+        let dns = join(config.dns.iter().filter(|ip| is_family(ip)));
+        // the method has to be changed after the addresses when switching to
+        // auto and before when switching to manual
+        let settings = [
+            (format!("{}.addresses", family), addresses.join(",")),
+            (format!("{}.gateway", family), gateway),
+            (format!("{}.dns", family), dns),
+        ];
+        if method == "manual" {
+            args.extend([format!("{}.method", family), method.to_string()]);
+            args.extend(settings.into_iter().flat_map(|(k, v)| [k, v]));
+        } else {
+            args.extend(settings.into_iter().flat_map(|(k, v)| [k, v]));
+            args.extend([format!("{}.method", family), method.to_string()]);
+        }
+    }
+    args
+}
+
+/// NetworkManager, configuring the connection profile created by the
+/// NetworkManager wifi backend. Thus a network has to be connected first; the
+/// backend keeps the IP settings when it replaces the profile on connect.
+pub struct NetworkManager {
+    interface: String,
+}
+
+impl NetworkManager {
+    pub fn new(interface: String) -> NetworkManager {
+        NetworkManager { interface }
+    }
+}
+
+#[async_trait]
+impl NetworkConfig for NetworkManager {
+    async fn apply(&self, config: &IpConfig) -> Result<(), String> {
+        let args = modify_args(config);
+        info!("Running nmcli {}", args.join(" "));
+        run(
+            "nmcli",
+            &args.iter().map(String::as_str).collect::<Vec<&str>>(),
+        )
+        .await?;
+        run("nmcli", &["device", "reapply", &self.interface]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_modify_args() {
+        let config = IpConfig::parse(
+            Method::Static,
+            b"192.168.0.2/24",
+            b"192.168.0.1",
+            b"1.1.1.1",
+        )
+        .unwrap();
+        assert_eq!(
+            modify_args(&config).join(" "),
+            "connection modify wifi-commissioning-gatt \
+             ipv4.method manual ipv4.addresses 192.168.0.2/24 ipv4.gateway 192.168.0.1 ipv4.dns 1.1.1.1 \
+             ipv6.addresses  ipv6.gateway  ipv6.dns  ipv6.method auto"
+        );
+    }
+}
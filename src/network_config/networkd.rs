@@ -0,0 +1,90 @@
+use super::{run, IpConfig, Method, NetworkConfig};
+use async_trait::async_trait;
+use log::info;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+const NETWORK_DIR: &str = "/etc/systemd/network";
+
+fn render(interface: &str, config: &IpConfig) -> String {
+    let mut network = String::new();
+    writeln!(
+        &mut network,
+        "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+         [Match]\n\
+         Name={}\n\n\
+         [Network]",
+        interface
+    )
+    .unwrap();
+    match config.method {
+        Method::Dhcp => network.push_str("DHCP=yes\n"),
+        Method::Static => {
+            for (ip, prefix) in &config.addresses {
+                writeln!(&mut network, "Address={}/{}", ip, prefix).unwrap();
+            }
+            for gateway in &config.gateways {
+                writeln!(&mut network, "Gateway={}", gateway).unwrap();
+            }
+        }
+    }
+    for dns in &config.dns {
+        writeln!(&mut network, "DNS={}", dns).unwrap();
+    }
+    network
+}
+
+/// systemd-networkd, configured by a .network file taking precedence over the
+/// ones shipped with the image.
+pub struct Networkd {
+    interface: String,
+}
+
+impl Networkd {
+    pub fn new(interface: String) -> Networkd {
+        Networkd { interface }
+    }
+}
+
+#[async_trait]
+impl NetworkConfig for Networkd {
+    async fn apply(&self, config: &IpConfig) -> Result<(), String> {
+        let path = PathBuf::from(NETWORK_DIR).join(format!(
+            "10-wifi-commissioning-gatt-{}.network",
+            self.interface
+        ));
+        info!("Writing {}", path.display());
+        tokio::fs::write(&path, render(&self.interface, config))
+            .await
+            .map_err(|e| format!("Writing {} failed: {}", path.display(), e))?;
+        run("networkctl", &["reload"]).await?;
+        run("networkctl", &["reconfigure", &self.interface]).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let config = IpConfig::parse(
+            Method::Static,
+            b"192.168.0.2/24 fd00::2/64",
+            b"192.168.0.1",
+            b"1.1.1.1",
+        )
+        .unwrap();
+        assert_eq!(
+            render("wlan0", &config),
+            "# Managed by wifi-commissioning-gatt-service, do not edit.\n\
+             [Match]\n\
+             Name=wlan0\n\n\
+             [Network]\n\
+             Address=192.168.0.2/24\n\
+             Address=fd00::2/64\n\
+             Gateway=192.168.0.1\n\
+             DNS=1.1.1.1\n"
+        );
+    }
+}
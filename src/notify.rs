@@ -0,0 +1,41 @@
+use bluer::gatt::local::CharacteristicNotifier;
+use log::{error, info};
+
+// Notification sessions of a characteristic. BlueZ starts a session for the
+// first subscribing device and forwards the notifications to every
+// subscribed device, and a device subscribing later may start another
+// session, so all sessions are kept until they are stopped or fail.
+// The notifier doesn't tell which device subscribed, so authorization can
+// only be checked when a session is started, not per device.
+#[derive(Default)]
+pub struct Notifiers {
+    notifiers: Vec<CharacteristicNotifier>,
+}
+
+impl Notifiers {
+    pub fn add(&mut self, notifier: CharacteristicNotifier) {
+        self.notifiers.retain(|notifier| !notifier.is_stopped());
+        self.notifiers.push(notifier);
+    }
+
+    // Any session that hasn't been stopped, so it's worth preparing a value.
+    pub fn is_active(&self) -> bool {
+        self.notifiers.iter().any(|notifier| !notifier.is_stopped())
+    }
+
+    // Notifies all sessions, dropping the stopped and failed ones.
+    pub async fn notify(&mut self, value: &[u8]) {
+        let mut alive = vec![];
+        for mut notifier in self.notifiers.drain(..) {
+            if notifier.is_stopped() {
+                info!("Notification session stopped");
+                continue;
+            }
+            match notifier.notify(value.to_vec()).await {
+                Ok(()) => alive.push(notifier),
+                Err(err) => error!("Notification stream error: {}", &err),
+            }
+        }
+        self.notifiers = alive;
+    }
+}
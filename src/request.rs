@@ -0,0 +1,51 @@
+use bluer::gatt::local::{CharacteristicReadRequest, CharacteristicWriteRequest};
+
+// The characteristic handlers only depend on this view of a request, since
+// bluer's request types cannot be constructed outside of bluer, e.g. in tests.
+pub trait Request: std::fmt::Debug + Send {
+    fn offset(&self) -> u16;
+    fn mtu(&self) -> u16;
+}
+
+impl Request for CharacteristicReadRequest {
+    fn offset(&self) -> u16 {
+        self.offset
+    }
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
+
+impl Request for CharacteristicWriteRequest {
+    fn offset(&self) -> u16 {
+        self.offset
+    }
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
+
+#[cfg(test)]
+#[derive(Debug)]
+pub struct TestRequest {
+    pub offset: u16,
+    pub mtu: u16,
+}
+
+#[cfg(test)]
+impl Default for TestRequest {
+    fn default() -> TestRequest {
+        // minimum ATT MTU
+        TestRequest { offset: 0, mtu: 23 }
+    }
+}
+
+#[cfg(test)]
+impl Request for TestRequest {
+    fn offset(&self) -> u16 {
+        self.offset
+    }
+    fn mtu(&self) -> u16 {
+        self.mtu
+    }
+}
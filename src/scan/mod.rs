@@ -1,24 +1,50 @@
 use crate::authorize;
+use crate::backend::{AccessPoint, WifiBackend};
+use crate::notify::Notifiers;
+use crate::request::Request;
 mod scan_utils;
 use bluer::gatt::local::{
     characteristic_control, service_control, Characteristic, CharacteristicNotifier,
-    CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead,
-    CharacteristicReadRequest, CharacteristicWrite, CharacteristicWriteMethod,
-    CharacteristicWriteRequest, ReqError, ReqResult, Service,
+    CharacteristicNotify, CharacteristicNotifyMethod, CharacteristicRead, CharacteristicWrite,
+    CharacteristicWriteMethod, ReqError, ReqResult, Service,
 };
 use enclose::enclose;
 use futures::FutureExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use scan_utils::{ResultFormat, ScanContext, ScanFilter};
 use std::convert::TryFrom;
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 
+// default length of the records, the minimum when derived from the MTU
 const RESULT_FIELD_LENGTH: usize = 100;
+// maximum length of a GATT attribute value
+const RESULT_FIELD_MAX_LENGTH: usize = 512;
+// minimum length of the records requested by a client, the ATT payload at the minimum MTU
+const RESULT_FIELD_MIN_LENGTH: usize = 20;
+// maximum number of records with an u8 and an u16 record index
+const MAX_RECORDS_U8: usize = 254;
+const MAX_RECORDS_U16: usize = u16::MAX as usize;
 
 pub const SCAN_SERVICE_UUID: uuid::Uuid = uuid::Uuid::from_u128(0xd69a37ee1d8a4329bd2425db4af3c863);
 const STATUS_SCAN_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa0);
 const SELECT_SCAN_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa1);
 const RESULT_SCAN_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076faa2);
+const PARAMS_SCAN_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab3);
+const FORMAT_SCAN_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab4);
+const RECORD_SIZE_SCAN_CHAR_UUID: uuid::Uuid =
+    uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab5);
+const STREAM_SCAN_CHAR_UUID: uuid::Uuid = uuid::Uuid::from_u128(0x811ce66622e04a6da50f0c78e076fab6);
+// sequence number (u16) and flags (u8) preceding the data of a stream chunk
+const STREAM_HEADER_LENGTH: usize = 3;
+const STREAM_FLAG_LAST: u8 = 0x1;
+// ATT header of a notification
+const ATT_NOTIFY_HEADER_LENGTH: usize = 3;
+// ATT header of a read response
+const ATT_READ_HEADER_LENGTH: usize = 1;
 
 #[derive(Clone, Copy)]
 #[repr(u8)]
@@ -60,9 +86,10 @@ struct ScanSharedData {
     result_scan_value: Mutex<Vec<u8>>,
     // Holds the whole scan results, before split into fields that is done into result_scan_value
     results: Mutex<Vec<u8>>,
-    // Number of fields that splitting 'results' into RESULT_FIELD_LENGTH sized fields yielded
-    select_max_records: Mutex<u8>,
-    // Scan select result, u8
+    // Number of fields that splitting 'results' into record_size sized fields yielded
+    select_max_records: Mutex<u16>,
+    // Scan select result, u8, or u16 little endian if selected by the
+    // format characteristic when the scan was started
     // After a scan has finished (status 2), the client shall read this
     // characteristic to query the number of records the client needs
     // to read to capture all the scan output. The client will when write
@@ -70,39 +97,87 @@ struct ScanSharedData {
     // then read the result characteristic (see below) to fetch the record,
     // and then increment this characteristic until all records have been read.
     select_scan_value: Mutex<Vec<u8>>,
-    // Notifier instance for status_scan_value. Only one notification client is supported.
-    status_scan_notify_opt: Mutex<Option<CharacteristicNotifier>>,
+    // Notifier instances for status_scan_value
+    status_scan_notifiers: Mutex<Notifiers>,
+    // Background task of the running scan
+    scan_task: Mutex<Option<JoinHandle<()>>>,
+    // Scan parameters, applied to the results of the next scan, see ScanFilter::parse
+    // Sequence of type (u8), length (u8) and value:
+    // 1: SSID prefix
+    // 2: minimum RSSI in dBm, i8
+    // 3: bands, u8 mask, 2.4 GHz: 0x1, 5 GHz: 0x2, 6 GHz: 0x4, 60 GHz: 0x8
+    // 4: security, u8 mask, open: 0x1, WEP: 0x2, WPA: 0x4, WPA2: 0x8, WPA3: 0x10, OWE: 0x20, EAP: 0x40
+    // 5: maximum number of results, u8
+    // 6: maximum age of cached results in seconds, u16 little endian, 0 to always scan
+    params_scan_value: Mutex<Vec<u8>>,
+    // Format of the results of the next scan, 2 bytes, the second is optional on write
+    // [0]: encoding, 0: JSON, 1: TLV, see README
+    // [1]: size of the record index of the select characteristic, 1 or 2
+    // Results exceeding the number of records are truncated.
+    format_scan_value: Mutex<Vec<u8>>,
+    // Length of the records of the current results, set when the scan is
+    // started from the preference or the MTU
+    record_size: Mutex<usize>,
+    // Record size requested by the client, u16 little endian, 0 to derive it from the MTU
+    record_size_preference: Mutex<u16>,
+    // Notifier instances for the stream characteristic. After a scan finished,
+    // the results are notified in chunks of sequence number (u16 little
+    // endian), flags (u8, 0x1: last chunk) and data. The client can write
+    // sequence numbers (u16 little endian each) to request missing chunks again.
+    stream_scan_notifiers: Mutex<Notifiers>,
+    // Length of the data of a stream chunk, fitting into a notification at
+    // the MTU of the request starting the scan
+    stream_chunk_size: Mutex<usize>,
+    // Limit of the number of results configured on the server
+    max_results: Option<usize>,
+    // Access points found by the last scan and when it finished
+    cache: Mutex<Option<(Instant, Vec<AccessPoint>)>>,
+    // Interval of the background scans, cached results up to this age are
+    // returned by default
+    scan_interval: Option<Duration>,
     authorized: Arc<Mutex<dyn Authorized + Send + Sync>>,
-    interface: String,
+    backend: Arc<dyn WifiBackend + Send + Sync>,
 }
 
 impl ScanSharedData {
-    fn new(interf: String, auth: Arc<Mutex<dyn Authorized + Send + Sync>>) -> ScanSharedData {
+    fn new(
+        backend: Arc<dyn WifiBackend + Send + Sync>,
+        max_results: Option<usize>,
+        scan_interval: Option<Duration>,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> ScanSharedData {
         ScanSharedData {
             status_scan_value: Mutex::new(vec![ScanState::Idle as u8]),
             result_scan_value: Mutex::new(vec![0; RESULT_FIELD_LENGTH]),
             results: Mutex::new(vec![]),
-            select_max_records: Mutex::new(0u8),
+            select_max_records: Mutex::new(0u16),
             select_scan_value: Mutex::new(vec![0x00]),
-            status_scan_notify_opt: Mutex::new(Option::None),
+            status_scan_notifiers: Mutex::new(Notifiers::default()),
+            scan_task: Mutex::new(Option::None),
+            params_scan_value: Mutex::new(vec![]),
+            format_scan_value: Mutex::new(vec![ResultFormat::Json as u8, 1]),
+            record_size: Mutex::new(RESULT_FIELD_LENGTH),
+            record_size_preference: Mutex::new(0u16),
+            stream_scan_notifiers: Mutex::new(Notifiers::default()),
+            stream_chunk_size: Mutex::new(0),
+            max_results,
+            cache: Mutex::new(Option::None),
+            scan_interval,
             authorized: auth,
-            interface: interf,
+            backend,
         }
     }
 }
 
-async fn read_result(
-    shared: Arc<ScanSharedData>,
-    req: CharacteristicReadRequest,
-) -> ReqResult<Vec<u8>> {
+async fn read_result(shared: Arc<ScanSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Scan result read no auth {:?}", &req);
         return Err(ReqError::NotAuthorized);
     }
     info!("Scan result read request {:?}", &req);
     let result_scan_value = shared.result_scan_value.lock().await.clone();
-    let offset = req.offset as usize;
-    let mtu = req.mtu as usize;
+    let offset = req.offset() as usize;
+    let mtu = req.mtu() as usize;
     if offset > result_scan_value.len() {
         error!("Scan result returning invalid offset");
         return Err(ReqError::InvalidOffset);
@@ -117,10 +192,7 @@ async fn read_result(
     Ok(vector)
 }
 
-async fn read_status(
-    shared: Arc<ScanSharedData>,
-    req: CharacteristicReadRequest,
-) -> ReqResult<Vec<u8>> {
+async fn read_status(shared: Arc<ScanSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Scan status read no auth {:?}", &req);
         return Err(ReqError::NotAuthorized);
@@ -131,10 +203,166 @@ async fn read_status(
     Ok(status_scan_value)
 }
 
+// Scans and stores the found access points in the cache.
+async fn refresh_cache(shared: &ScanSharedData) -> Result<Vec<AccessPoint>, String> {
+    let aps = shared.backend.scan().await?;
+    *shared.cache.lock().await = Some((Instant::now(), aps.clone()));
+    Ok(aps)
+}
+
+// Returns the cached access points if they are not older than max_age,
+// along with their age, otherwise scans.
+async fn cached_scan(
+    shared: &ScanSharedData,
+    max_age: Option<Duration>,
+) -> Result<(Vec<AccessPoint>, Option<Duration>), String> {
+    if let (Some(max_age), Some((time, aps))) = (max_age, shared.cache.lock().await.as_ref()) {
+        let age = time.elapsed();
+        if age <= max_age {
+            info!("Returning scan results cached {}s ago", age.as_secs());
+            return Ok((aps.clone(), Some(age)));
+        }
+    }
+    Ok((refresh_cache(shared).await?, None))
+}
+
+// Looks up the known and the connected network, which are only marked in
+// the results, so failures don't fail the scan.
+async fn scan_context(shared: &ScanSharedData, age: Option<Duration>) -> ScanContext {
+    let known = shared.backend.known_networks().await.unwrap_or_else(|e| {
+        warn!("Listing known networks failed: {}", e);
+        vec![]
+    });
+    let connected = match shared.backend.status().await {
+        Ok(status) if status.completed => status.ssid,
+        Ok(_) => None,
+        Err(e) => {
+            warn!("Querying the connection status failed: {}", e);
+            None
+        }
+    };
+    ScanContext {
+        age,
+        known,
+        connected,
+    }
+}
+
+async fn run_scan(
+    shared: Arc<ScanSharedData>,
+    filter: ScanFilter,
+    format: ResultFormat,
+    index_size: usize,
+    record_size: usize,
+) {
+    let max_records = if index_size == 2 {
+        MAX_RECORDS_U16
+    } else {
+        MAX_RECORDS_U8
+    };
+    let max_age = filter.max_age.or(shared.scan_interval);
+    let scan_task_result = match cached_scan(&shared, max_age).await {
+        Ok((aps, age)) => {
+            let context = scan_context(&shared, age).await;
+            scan_utils::results(aps, &context, &filter, format, max_records * record_size)
+        }
+        Err(e) => Err(e),
+    };
+    let mut status_scan_value = shared.status_scan_value.lock().await;
+    // the client may have discarded the scan meanwhile
+    if !matches!(
+        ScanState::try_from(status_scan_value[0]),
+        Ok(ScanState::Scan)
+    ) {
+        info!("Scan was cancelled, discarding results");
+        return;
+    }
+    let mut results_store = shared.results.lock().await;
+    let mut select_max_records = shared.select_max_records.lock().await;
+    let mut select_scan_value = shared.select_scan_value.lock().await;
+    match scan_task_result {
+        Ok(results) => {
+            // scan finished, the results were truncated so that they fit
+            status_scan_value[0] = ScanState::Finished as u8;
+            let max_fields = results.len().div_ceil(record_size) as u16;
+            *select_max_records = max_fields;
+            *select_scan_value = max_fields.to_le_bytes()[..index_size].to_vec();
+            *results_store = results;
+        }
+        Err(e) => {
+            error!("Scan failed: {:?}", e);
+            status_scan_value[0] = ScanState::Error as u8; // scan failed
+        }
+    }
+    let mut notifiers = shared.status_scan_notifiers.lock().await;
+    if notifiers.is_active() {
+        info!("Notifying scan status with value {:x?}", &status_scan_value);
+        notifiers.notify(&status_scan_value).await;
+    }
+    if status_scan_value[0] == ScanState::Finished as u8 {
+        let chunks = stream_chunks(&results_store, *shared.stream_chunk_size.lock().await);
+        // don't block the other requests while streaming
+        drop((
+            notifiers,
+            results_store,
+            select_max_records,
+            select_scan_value,
+            status_scan_value,
+        ));
+        let mut notifiers = shared.stream_scan_notifiers.lock().await;
+        stream(&mut notifiers, &chunks, 0..chunks.len() as u16).await;
+    }
+}
+
+// Splits the results into stream chunks, see stream_scan_notifiers. With
+// the u16 sequence number, the results can't exceed 65535 chunks, which
+// the records limit the results to in practice as well.
+fn stream_chunks(results: &[u8], chunk_size: usize) -> Vec<Vec<u8>> {
+    // e.g. TLV encoded results without networks, the client still needs the last chunk
+    if results.is_empty() {
+        return vec![vec![0, 0, STREAM_FLAG_LAST]];
+    }
+    let count = results.len().div_ceil(chunk_size).min(u16::MAX as usize);
+    results
+        .chunks(chunk_size)
+        .take(count)
+        .enumerate()
+        .map(|(sequence, data)| {
+            let mut chunk = (sequence as u16).to_le_bytes().to_vec();
+            chunk.push(if sequence + 1 == count {
+                STREAM_FLAG_LAST
+            } else {
+                0
+            });
+            chunk.extend_from_slice(data);
+            chunk
+        })
+        .collect()
+}
+
+async fn stream(
+    notifiers: &mut Notifiers,
+    chunks: &[Vec<u8>],
+    sequences: impl IntoIterator<Item = u16>,
+) {
+    for sequence in sequences {
+        // stop once all subscribers are gone
+        if !notifiers.is_active() {
+            return;
+        }
+        let chunk = match chunks.get(sequence as usize) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+        debug!("Notifying scan stream with value {:x?}", chunk);
+        notifiers.notify(chunk).await;
+    }
+}
+
 async fn write_status(
     shared: Arc<ScanSharedData>,
     new_value: Vec<u8>,
-    req: CharacteristicWriteRequest,
+    req: impl Request,
 ) -> ReqResult<()> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Scan status write no auth {:?}", &req);
@@ -155,40 +383,39 @@ async fn write_status(
     };
     let mut status_scan_value = shared.status_scan_value.lock().await;
     let old_state = ScanState::try_from(status_scan_value[0]).unwrap(); // this cannot fail
-    status_scan_value[0] = new_state as u8;
     match (old_state, new_state) {
         (ScanState::Idle, ScanState::Scan) => {
-            // Start scan
-            let scan_task_result = scan_utils::scan(shared.interface.clone()).await;
-            let mut results_store = shared.results.lock().await;
-            let mut select_max_records = shared.select_max_records.lock().await;
-            let mut select_scan_value = shared.select_scan_value.lock().await;
-            match scan_task_result {
-                Ok(json) => {
-                    status_scan_value[0] = ScanState::Finished as u8; // scan finished
-                    let max_fields = json.len().div_ceil(RESULT_FIELD_LENGTH);
-                    if max_fields < 255 {
-                        *select_max_records = max_fields as u8;
-                        select_scan_value[0] = max_fields as u8;
-                        *results_store = json;
-                    } else {
-                        error!("Scan failed due to too many results");
-                        status_scan_value[0] = ScanState::Error as u8; // scan failed
-                    }
-                }
+            let filter = match ScanFilter::parse(&shared.params_scan_value.lock().await) {
+                Ok(filter) => filter.limit(shared.max_results),
                 Err(e) => {
-                    error!("Scan failed: {:?}", e);
-                    status_scan_value[0] = ScanState::Error as u8; // scan failed
-                }
-            }
-            let mut opt = shared.status_scan_notify_opt.lock().await;
-            if let Some(writer) = opt.as_mut() {
-                info!("Notifying scan status with value {:x?}", &status_scan_value);
-                if let Err(err) = writer.notify(status_scan_value.clone()).await {
-                    error!("Notification stream error: {}", &err);
-                    *opt = None;
+                    error!("Scan parameters invalid: {}", e);
+                    return Err(ReqError::NotSupported);
                 }
-            }
+            };
+            // validated when written
+            let format_scan_value = shared.format_scan_value.lock().await.clone();
+            let format = ResultFormat::try_from(format_scan_value[0]).unwrap();
+            let index_size = format_scan_value[1] as usize;
+            // a record per read request, unless the client prefers otherwise
+            let record_size = match *shared.record_size_preference.lock().await {
+                0 => (req.mtu() as usize)
+                    .saturating_sub(ATT_READ_HEADER_LENGTH)
+                    .clamp(RESULT_FIELD_LENGTH, RESULT_FIELD_MAX_LENGTH),
+                preference => preference as usize,
+            };
+            *shared.record_size.lock().await = record_size;
+            *shared.stream_chunk_size.lock().await = (req.mtu() as usize)
+                .saturating_sub(ATT_NOTIFY_HEADER_LENGTH + STREAM_HEADER_LENGTH)
+                .max(1);
+            // Start scan, which takes seconds, so don't block the request
+            status_scan_value[0] = new_state as u8;
+            *shared.scan_task.lock().await = Some(tokio::spawn(run_scan(
+                shared.clone(),
+                filter,
+                format,
+                index_size,
+                record_size,
+            )));
         }
         (_old, ScanState::Scan) => {
             // invalid
@@ -199,13 +426,17 @@ async fn write_status(
             return Err(ReqError::NotSupported);
         }
         (_old, ScanState::Idle) => {
-            // Discard results
+            status_scan_value[0] = new_state as u8;
+            // Cancel a running scan and discard results
+            if let Some(task) = shared.scan_task.lock().await.take() {
+                task.abort();
+            }
             let mut results_store = shared.results.lock().await;
             *results_store = vec![0; RESULT_FIELD_LENGTH]; // clear results
             let mut select_max_records = shared.select_max_records.lock().await;
-            *select_max_records = 0u8;
+            *select_max_records = 0u16;
             let mut select_scan_value = shared.select_scan_value.lock().await;
-            select_scan_value[0] = 0u8;
+            select_scan_value.fill(0u8);
         }
         (_old, ScanState::Finished | ScanState::Error) => {
             // unreachable
@@ -224,14 +455,10 @@ async fn start_notify_status(shared: Arc<ScanSharedData>, notifier: Characterist
         "Status scan accepting notify, confirming {}",
         notifier.confirming()
     );
-    let mut opt = shared.status_scan_notify_opt.lock().await;
-    *opt = Some(notifier);
+    shared.status_scan_notifiers.lock().await.add(notifier);
 }
 
-async fn read_select(
-    shared: Arc<ScanSharedData>,
-    req: CharacteristicReadRequest,
-) -> ReqResult<Vec<u8>> {
+async fn read_select(shared: Arc<ScanSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Scan select read no auth {:?}", &req);
         return Err(ReqError::NotAuthorized);
@@ -247,7 +474,7 @@ async fn read_select(
 async fn write_select(
     shared: Arc<ScanSharedData>,
     new_value: Vec<u8>,
-    req: CharacteristicWriteRequest,
+    req: impl Request,
 ) -> ReqResult<()> {
     if !shared.authorized.lock().await.is_authorized().await {
         error!("Scan select write no auth {:?}", &req);
@@ -255,12 +482,18 @@ async fn write_select(
     }
     info!("Scan select write request {:?}", &req);
     debug!(" with value {:x?}", &new_value);
-    if new_value.len() > 1 {
-        error!("Scan select write invalid length.");
-        return Err(ReqError::InvalidValueLength);
-    }
+    let mut select_scan_value = shared.select_scan_value.lock().await;
+    // the record index has the size selected when the scan was started
+    let index = match new_value[..] {
+        [index] if select_scan_value.len() == 1 => index as u16,
+        [low, high] if select_scan_value.len() == 2 => u16::from_le_bytes([low, high]),
+        _ => {
+            error!("Scan select write invalid length.");
+            return Err(ReqError::InvalidValueLength);
+        }
+    };
     let select_max_records = shared.select_max_records.lock().await;
-    if new_value[0] >= *select_max_records {
+    if index >= *select_max_records {
         error!(
             "Scan status write invalid index, expected to be < {:x?}.",
             select_max_records
@@ -269,28 +502,259 @@ async fn write_select(
     }
     let mut results_store = shared.result_scan_value.lock().await;
     let results_all = shared.results.lock().await;
-    let offset: usize = (new_value[0] as usize) * RESULT_FIELD_LENGTH;
-    let mut size: usize = RESULT_FIELD_LENGTH;
+    let record_size = *shared.record_size.lock().await;
+    let offset: usize = (index as usize) * record_size;
+    let mut size: usize = record_size;
     if offset + size > results_all.len() {
         size = results_all.len() - offset;
     }
     let slice = &results_all[offset..(offset + size)];
     let vector: Vec<u8> = slice.to_vec();
     *results_store = vector;
-    let mut select_scan_value = shared.select_scan_value.lock().await;
     *select_scan_value = new_value;
     Ok(())
 }
+
+async fn read_params(shared: Arc<ScanSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan params read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let params_scan_value = shared.params_scan_value.lock().await.clone();
+    info!("Scan params read request {:?}", &req);
+    debug!(" with value {:x?}", &params_scan_value);
+    let offset = req.offset() as usize;
+    let mtu = req.mtu() as usize;
+    if offset > params_scan_value.len() {
+        error!("Scan params returning invalid offset");
+        return Err(ReqError::InvalidOffset);
+    }
+    let size = (params_scan_value.len() - offset).min(mtu);
+    Ok(params_scan_value[offset..(offset + size)].to_vec())
+}
+
+async fn write_params(
+    shared: Arc<ScanSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan params write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Scan params write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    let offset = req.offset() as usize;
+    let len = new_value.len();
+    if len + offset > scan_utils::PARAMS_MAX_LENGTH {
+        error!("Scan params write invalid length.");
+        return Err(ReqError::InvalidValueLength);
+    }
+    let mut params_scan_value = shared.params_scan_value.lock().await;
+    // variable length like the SSID, see connect service; the parameters
+    // are validated when the scan is started
+    if offset == 0 {
+        params_scan_value.clear();
+    }
+    if offset > params_scan_value.len() {
+        error!("Scan params write invalid offset.");
+        return Err(ReqError::InvalidOffset);
+    }
+    let endoffset = (offset + len).min(params_scan_value.len());
+    params_scan_value.splice(offset..endoffset, new_value.iter().cloned());
+    Ok(())
+}
+
+async fn read_format(shared: Arc<ScanSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan format read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let format_scan_value = shared.format_scan_value.lock().await.clone();
+    info!("Scan format read request {:?}", &req);
+    debug!(" with value {:x?}", &format_scan_value);
+    Ok(format_scan_value)
+}
+
+async fn write_format(
+    shared: Arc<ScanSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan format write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Scan format write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    // the index size is optional for clients not knowing about it
+    let (format, index_size) = match new_value[..] {
+        [format] => (format, 1),
+        [format, index_size] => (format, index_size),
+        _ => {
+            error!("Scan format write invalid length.");
+            return Err(ReqError::InvalidValueLength);
+        }
+    };
+    if let Err(e) = ResultFormat::try_from(format) {
+        error!("Scan format write failed: {}", e);
+        return Err(ReqError::NotSupported);
+    }
+    if index_size != 1 && index_size != 2 {
+        error!("Scan format write invalid index size {}.", index_size);
+        return Err(ReqError::NotSupported);
+    }
+    *shared.format_scan_value.lock().await = vec![format, index_size];
+    Ok(())
+}
+
+async fn read_record_size(shared: Arc<ScanSharedData>, req: impl Request) -> ReqResult<Vec<u8>> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan record size read no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    let record_size = *shared.record_size.lock().await as u16;
+    info!("Scan record size read request {:?}", &req);
+    debug!(" with value {}", record_size);
+    Ok(record_size.to_le_bytes().to_vec())
+}
+
+async fn write_record_size(
+    shared: Arc<ScanSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan record size write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Scan record size write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    let preference = match new_value[..] {
+        [low, high] => u16::from_le_bytes([low, high]),
+        _ => {
+            error!("Scan record size write invalid length.");
+            return Err(ReqError::InvalidValueLength);
+        }
+    };
+    if preference != 0
+        && !(RESULT_FIELD_MIN_LENGTH..=RESULT_FIELD_MAX_LENGTH).contains(&(preference as usize))
+    {
+        error!("Scan record size write invalid size {}.", preference);
+        return Err(ReqError::NotSupported);
+    }
+    *shared.record_size_preference.lock().await = preference;
+    Ok(())
+}
+
+async fn start_notify_stream(shared: Arc<ScanSharedData>, notifier: CharacteristicNotifier) {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Stream scan notify no auth");
+        return;
+    }
+    info!(
+        "Stream scan accepting notify, confirming {}",
+        notifier.confirming()
+    );
+    shared.stream_scan_notifiers.lock().await.add(notifier);
+}
+
+async fn write_stream(
+    shared: Arc<ScanSharedData>,
+    new_value: Vec<u8>,
+    req: impl Request,
+) -> ReqResult<()> {
+    if !shared.authorized.lock().await.is_authorized().await {
+        error!("Scan stream write no auth {:?}", &req);
+        return Err(ReqError::NotAuthorized);
+    }
+    info!("Scan stream write request {:?}", &req);
+    debug!(" with value {:x?}", &new_value);
+    if new_value.is_empty() || new_value.len() % 2 != 0 {
+        error!("Scan stream write invalid length.");
+        return Err(ReqError::InvalidValueLength);
+    }
+    let status_scan_value = shared.status_scan_value.lock().await;
+    if status_scan_value[0] != ScanState::Finished as u8 {
+        error!("Scan stream write without results.");
+        return Err(ReqError::NotSupported);
+    }
+    let chunks = stream_chunks(
+        &shared.results.lock().await,
+        *shared.stream_chunk_size.lock().await,
+    );
+    let sequences: Vec<u16> = new_value
+        .chunks(2)
+        .map(|sequence| u16::from_le_bytes([sequence[0], sequence[1]]))
+        .collect();
+    if sequences
+        .iter()
+        .any(|sequence| *sequence as usize >= chunks.len())
+    {
+        error!("Scan stream write invalid sequence number.");
+        return Err(ReqError::NotSupported);
+    }
+    // resend after the write was confirmed
+    let shared = shared.clone();
+    tokio::spawn(async move {
+        let mut notifiers = shared.stream_scan_notifiers.lock().await;
+        stream(&mut notifiers, &chunks, sequences).await;
+    });
+    Ok(())
+}
+
 use authorize::Authorized;
 
 pub struct ScanService {
     shared: Arc<ScanSharedData>,
+    // Background scan refreshing the cache
+    refresh_task: Option<JoinHandle<()>>,
 }
 
 impl ScanService {
-    pub fn new(interface: String, auth: Arc<Mutex<dyn Authorized + Send + Sync>>) -> ScanService {
+    pub fn new(
+        backend: Arc<dyn WifiBackend + Send + Sync>,
+        max_results: Option<usize>,
+        scan_interval: Option<Duration>,
+        auth: Arc<Mutex<dyn Authorized + Send + Sync>>,
+    ) -> ScanService {
         ScanService {
-            shared: Arc::new(ScanSharedData::new(interface, auth)),
+            shared: Arc::new(ScanSharedData::new(
+                backend,
+                max_results,
+                scan_interval,
+                auth,
+            )),
+            refresh_task: None,
+        }
+    }
+
+    // Scans in the background once the cache is older than the scan interval.
+    // Paused while connected, as scanning disturbs the connection.
+    pub async fn tick(&mut self, connected: bool) {
+        let scan_interval = match self.shared.scan_interval {
+            Some(scan_interval) if !connected => scan_interval,
+            _ => return,
+        };
+        if self
+            .refresh_task
+            .as_ref()
+            .is_some_and(|task| !task.is_finished())
+        {
+            return;
+        }
+        let expired = match self.shared.cache.lock().await.as_ref() {
+            Some((time, _)) => time.elapsed() >= scan_interval,
+            None => true,
+        };
+        if expired {
+            debug!("Refreshing scan cache");
+            let shared = self.shared.clone();
+            self.refresh_task = Some(tokio::spawn(async move {
+                if let Err(e) = refresh_cache(&shared).await {
+                    error!("Background scan failed: {:?}", e);
+                }
+            }));
         }
     }
     pub fn service_entry(&mut self) -> Service {
@@ -299,6 +763,11 @@ impl ScanService {
         let (_status_scan_char_control, status_scan_char_handle) = characteristic_control();
         let (_select_scan_char_control, select_scan_char_handle) = characteristic_control();
         let (_result_scan_char_control, result_scan_char_handle) = characteristic_control();
+        let (_params_scan_char_control, params_scan_char_handle) = characteristic_control();
+        let (_format_scan_char_control, format_scan_char_handle) = characteristic_control();
+        let (_record_size_scan_char_control, record_size_scan_char_handle) =
+            characteristic_control();
+        let (_stream_scan_char_control, stream_scan_char_handle) = characteristic_control();
         Service {
             uuid: SCAN_SERVICE_UUID,
             primary: true,
@@ -371,9 +840,640 @@ impl ScanService {
                     control_handle: result_scan_char_handle,
                     ..Default::default()
                 },
+                Characteristic {
+                    uuid: PARAMS_SCAN_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_params(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_params(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: params_scan_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: FORMAT_SCAN_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_format(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_format(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: format_scan_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: RECORD_SIZE_SCAN_CHAR_UUID,
+                    read: Some(CharacteristicRead {
+                        read: true,
+                        fun: Box::new(
+                            enclose!( (shared) move |req| read_record_size(shared.clone(), req).boxed()),
+                        ),
+                        ..Default::default()
+                    }),
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_record_size(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: record_size_scan_char_handle,
+                    ..Default::default()
+                },
+                Characteristic {
+                    uuid: STREAM_SCAN_CHAR_UUID,
+                    write: Some(CharacteristicWrite {
+                        write: true,
+                        method: CharacteristicWriteMethod::Fun(Box::new(
+                            enclose!( (shared) move |new_value, req| {
+                                let shared = shared.clone();
+                                write_stream(shared, new_value, req).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    notify: Some(CharacteristicNotify {
+                        notify: true,
+                        method: CharacteristicNotifyMethod::Fun(Box::new(
+                            enclose!( (shared) move|notifier| {
+                                let shared = shared.clone();
+                                start_notify_stream(shared, notifier).boxed()
+                            }),
+                        )),
+                        ..Default::default()
+                    }),
+                    control_handle: stream_scan_char_handle,
+                    ..Default::default()
+                },
             ],
             control_handle: scan_service_handle,
             ..Default::default()
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorize::MockAuthorized;
+    use crate::backend::mock::{Mock, Script};
+    use crate::request::TestRequest;
+
+    fn service(script: Script) -> (ScanService, Arc<Mock>) {
+        let backend = Arc::new(Mock::new(script));
+        let service = ScanService::new(
+            backend.clone(),
+            None,
+            None,
+            Arc::new(Mutex::new(MockAuthorized(true))),
+        );
+        (service, backend)
+    }
+
+    fn access_point(ssid: &str) -> AccessPoint {
+        AccessPoint {
+            bssid: "01:02:03:04:05:06".to_string(),
+            frequency: 2412,
+            signal: -50,
+            flags: "[WPA2-PSK-CCMP][ESS]".to_string(),
+            ssid: ssid.as_bytes().to_vec(),
+        }
+    }
+
+    async fn finish_scan(shared: &Arc<ScanSharedData>) {
+        let task = shared.scan_task.lock().await.take().unwrap();
+        task.await.unwrap();
+    }
+
+    async fn read_all_results(shared: Arc<ScanSharedData>) -> Vec<u8> {
+        let select = read_select(shared.clone(), TestRequest::default())
+            .await
+            .unwrap();
+        let index_size = select.len();
+        let records = select
+            .iter()
+            .rev()
+            .fold(0u16, |records, byte| records << 8 | *byte as u16);
+        let mut results = vec![];
+        for record in 0..records {
+            let index = record.to_le_bytes()[..index_size].to_vec();
+            write_select(shared.clone(), index, TestRequest::default())
+                .await
+                .unwrap();
+            let mut offset = 0;
+            loop {
+                let req = TestRequest {
+                    offset,
+                    ..Default::default()
+                };
+                let part = read_result(shared.clone(), req).await.unwrap();
+                if part.is_empty() {
+                    break;
+                }
+                offset += part.len() as u16;
+                results.extend(part);
+            }
+        }
+        results
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scan() {
+        let aps: Vec<AccessPoint> = (0..10)
+            .map(|i| access_point(&format!("network {}", i)))
+            .collect();
+        let (service, backend) = service(Script {
+            scan: Ok(aps),
+            scan_duration: Duration::from_secs(3),
+            ..Default::default()
+        });
+        let shared = service.shared.clone();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        // the write doesn't wait for the scan
+        assert_eq!(
+            read_status(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![ScanState::Scan as u8]
+        );
+        finish_scan(&shared).await;
+        assert_eq!(backend.calls.lock().unwrap().scans, 1);
+        assert_eq!(
+            read_status(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![ScanState::Finished as u8]
+        );
+        let results = read_all_results(shared.clone()).await;
+        let json = String::from_utf8(results).unwrap();
+        assert!(json.starts_with(r#"[{"ssid":"network 0","rssi":"-50""#));
+        assert!(json.ends_with(
+            r#""ssid":"network 9","rssi":"-50","mac":"01:02:03:04:05:06","ch":"2412","freq":"2412","channel":"1","band":"2.4","quality":"83","sec":["wpa2"],"wps":"0","known":"0","connected":"0","count":"1"}]"#
+        ));
+
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            read_select(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![0]
+        );
+
+        // discarding a running scan
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        tokio::time::sleep(Duration::from_secs(5)).await;
+        assert_eq!(
+            read_status(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![ScanState::Idle as u8]
+        );
+        assert_eq!(
+            read_select(shared, TestRequest::default()).await.unwrap(),
+            vec![0]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_scan_cache() {
+        let backend = Arc::new(Mock::new(Script {
+            scan: Ok(vec![access_point("cached")]),
+            scan_duration: Duration::from_secs(3),
+            known_networks: Ok(vec![b"cached".to_vec()]),
+            ..Default::default()
+        }));
+        let mut service = ScanService::new(
+            backend.clone(),
+            None,
+            Some(Duration::from_secs(60)),
+            Arc::new(Mutex::new(MockAuthorized(true))),
+        );
+        let shared = service.shared.clone();
+        service.tick(false).await;
+        // no second scan while the first one is running
+        service.tick(false).await;
+        service.refresh_task.take().unwrap().await.unwrap();
+        assert_eq!(backend.calls.lock().unwrap().scans, 1);
+        tokio::time::sleep(Duration::from_secs(10)).await;
+        service.tick(false).await;
+        assert!(service.refresh_task.is_none());
+
+        // answered from the cache
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert_eq!(backend.calls.lock().unwrap().scans, 1);
+        let json = String::from_utf8(read_all_results(shared.clone()).await).unwrap();
+        assert!(json.ends_with(r#""known":"1","connected":"0","count":"1","age":"10"}]"#));
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        // the client asks for fresh results
+        write_params(shared.clone(), vec![6, 2, 0, 0], TestRequest::default())
+            .await
+            .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert_eq!(backend.calls.lock().unwrap().scans, 2);
+        let json = String::from_utf8(read_all_results(shared.clone()).await).unwrap();
+        assert!(json.ends_with(r#""count":"1"}]"#));
+
+        // paused while connected
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        service.tick(true).await;
+        assert!(service.refresh_task.is_none());
+        service.tick(false).await;
+        service.refresh_task.take().unwrap().await.unwrap();
+        assert_eq!(backend.calls.lock().unwrap().scans, 3);
+    }
+
+    #[tokio::test]
+    async fn test_scan_params() {
+        let aps = (0..10)
+            .map(|i| access_point(&format!("network {}", i)))
+            .collect();
+        let (service, _backend) = service(Script {
+            scan: Ok(aps),
+            ..Default::default()
+        });
+        let shared = service.shared.clone();
+        // SSID prefix split over two writes
+        write_params(shared.clone(), vec![1, 9], TestRequest::default())
+            .await
+            .unwrap();
+        let req = TestRequest {
+            offset: 2,
+            ..Default::default()
+        };
+        write_params(shared.clone(), b"network 7".to_vec(), req)
+            .await
+            .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        let json = String::from_utf8(read_all_results(shared.clone()).await).unwrap();
+        assert!(json.starts_with(r#"[{"ssid":"network 7""#));
+        assert_eq!(json.matches("ssid").count(), 1);
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        // same scan TLV encoded
+        write_format(
+            shared.clone(),
+            vec![ResultFormat::Tlv as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        let tlv = read_all_results(shared.clone()).await;
+        assert_eq!(&tlv[..13], b"\x01\x29\x01\x09network 7");
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        assert!(
+            write_format(shared.clone(), vec![2], TestRequest::default())
+                .await
+                .is_err()
+        );
+
+        write_params(shared.clone(), vec![5, 2, 0, 0], TestRequest::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            write_status(
+                shared.clone(),
+                vec![ScanState::Scan as u8],
+                TestRequest::default(),
+            )
+            .await,
+            Err(ReqError::NotSupported)
+        ));
+        assert_eq!(
+            read_status(shared, TestRequest::default()).await.unwrap(),
+            vec![ScanState::Idle as u8]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_record_size() {
+        let aps = (0..10)
+            .map(|i| access_point(&format!("network {}", i)))
+            .collect();
+        let (service, _backend) = service(Script {
+            scan: Ok(aps),
+            ..Default::default()
+        });
+        let shared = service.shared.clone();
+        let record_size = |shared: Arc<ScanSharedData>| async move {
+            read_record_size(shared, TestRequest::default())
+                .await
+                .unwrap()
+        };
+        assert_eq!(record_size(shared.clone()).await, vec![100, 0]);
+
+        // derived from the MTU of the request starting the scan
+        let req = TestRequest {
+            mtu: 247,
+            ..Default::default()
+        };
+        write_status(shared.clone(), vec![ScanState::Scan as u8], req)
+            .await
+            .unwrap();
+        finish_scan(&shared).await;
+        // a read response carries the MTU less its header
+        assert_eq!(record_size(shared.clone()).await, vec![246, 0]);
+        let records = read_select(shared.clone(), TestRequest::default())
+            .await
+            .unwrap()[0] as usize;
+        let json = read_all_results(shared.clone()).await;
+        assert_eq!(records, json.len().div_ceil(246));
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        write_record_size(shared.clone(), vec![20, 0], TestRequest::default())
+            .await
+            .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert_eq!(record_size(shared.clone()).await, vec![20, 0]);
+        assert_eq!(read_all_results(shared.clone()).await, json);
+
+        assert!(
+            write_record_size(shared.clone(), vec![10, 0], TestRequest::default())
+                .await
+                .is_err()
+        );
+        assert!(
+            write_record_size(shared, vec![0, 2, 0], TestRequest::default())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stream() {
+        let chunks = stream_chunks(b"0123456789", 4);
+        assert_eq!(
+            chunks,
+            vec![
+                b"\x00\x00\x000123".to_vec(),
+                b"\x01\x00\x004567".to_vec(),
+                b"\x02\x00\x0189".to_vec()
+            ]
+        );
+        assert_eq!(stream_chunks(b"", 4), vec![b"\x00\x00\x01".to_vec()]);
+
+        let (service, backend) = service(Script {
+            scan: Ok(vec![access_point("network")]),
+            ..Default::default()
+        });
+        let shared = service.shared.clone();
+        assert!(matches!(
+            write_stream(shared.clone(), vec![0, 0], TestRequest::default()).await,
+            Err(ReqError::NotSupported)
+        ));
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        // chunks fit into a notification at the minimum MTU
+        assert_eq!(*shared.stream_chunk_size.lock().await, 17);
+        write_stream(shared.clone(), vec![0, 0, 1, 0], TestRequest::default())
+            .await
+            .unwrap();
+        assert!(matches!(
+            write_stream(shared.clone(), vec![0], TestRequest::default()).await,
+            Err(ReqError::InvalidValueLength)
+        ));
+        assert!(matches!(
+            write_stream(shared.clone(), vec![0xff, 0], TestRequest::default()).await,
+            Err(ReqError::NotSupported)
+        ));
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        // TLV encoded results without networks are empty
+        backend.script.lock().unwrap().scan = Ok(vec![]);
+        write_format(
+            shared.clone(),
+            vec![ResultFormat::Tlv as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert!(shared.results.lock().await.is_empty());
+        write_stream(shared, vec![0, 0], TestRequest::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_scan_errors() {
+        let (service, backend) = service(Script {
+            scan: Err("SCAN failed.".to_string()),
+            ..Default::default()
+        });
+        let shared = service.shared.clone();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert_eq!(
+            read_status(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![ScanState::Error as u8]
+        );
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        // results exceeding 254 records
+        let aps = (0..1000)
+            .map(|i| access_point(&format!("network {}", i)))
+            .collect();
+        backend.script.lock().unwrap().scan = Ok(aps);
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert_eq!(
+            read_status(shared.clone(), TestRequest::default())
+                .await
+                .unwrap(),
+            vec![ScanState::Finished as u8]
+        );
+        // truncated to the strongest networks fitting into the records
+        let json = read_all_results(shared.clone()).await;
+        assert!(json.len() <= 254 * RESULT_FIELD_LENGTH);
+        let json: Vec<serde_json::Value> = serde_json::from_slice(&json).unwrap();
+        assert!(json.len() > 100 && json.len() < 1000);
+        write_status(
+            shared.clone(),
+            vec![ScanState::Idle as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+
+        // all results with an u16 record index
+        write_format(shared.clone(), vec![0, 2], TestRequest::default())
+            .await
+            .unwrap();
+        write_status(
+            shared.clone(),
+            vec![ScanState::Scan as u8],
+            TestRequest::default(),
+        )
+        .await
+        .unwrap();
+        finish_scan(&shared).await;
+        assert_eq!(
+            read_select(shared.clone(), TestRequest::default())
+                .await
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(
+            write_select(shared.clone(), vec![0], TestRequest::default())
+                .await
+                .is_err()
+        );
+        let json = read_all_results(shared.clone()).await;
+        let json: Vec<serde_json::Value> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.len(), 1000);
+        assert!(write_format(shared, vec![0, 3], TestRequest::default())
+            .await
+            .is_err());
+    }
+}
@@ -1,144 +1,632 @@
-use log::{debug, error, info};
-use std::fmt::Write;
-
-fn unescape_hex(ssid: &str) -> Vec<u8> {
-    let re = regex::bytes::Regex::new(r"\\(\\|(x([0-9a-fA-F]{2})))").unwrap();
-    let out = re.replace_all(ssid.as_bytes(), |caps: &regex::bytes::Captures| {
-        if caps[0] == [0x5Cu8, 0x5Cu8] {
-            [0x5Cu8]
-        } else {
-            [u8::from_str_radix(std::str::from_utf8(&caps[3]).unwrap(), 16).unwrap()]
+use crate::backend::AccessPoint;
+use log::{debug, warn};
+use serde::Serialize;
+use std::time::Duration;
+
+// Key managements offered by the access point, parsed from the flags in
+// wpa_supplicant notation, e.g. "[WPA-PSK-TKIP][WPA2-PSK+SAE-CCMP][WPS][ESS]"
+// yields ["wpa","wpa2","wpa3"] and WPS.
+fn parse_security(flags: &str) -> (Vec<&'static str>, bool) {
+    let mut security = vec![];
+    let mut wps = false;
+    for flag in flags.split(['[', ']']).filter(|flag| !flag.is_empty()) {
+        let mut parts = flag.split('-');
+        let (protocol, key_mgmt) = (parts.next().unwrap_or_default(), parts.next());
+        let found: Vec<&str> = match (protocol, key_mgmt) {
+            ("WEP", _) => vec!["wep"],
+            ("WPS", _) => {
+                wps = true;
+                vec![]
+            }
+            ("WPA" | "WPA2" | "RSN", Some(key_mgmt)) => key_mgmt
+                .split('+')
+                .filter_map(|key_mgmt| {
+                    if key_mgmt.contains("EAP") {
+                        Some("eap")
+                    } else if key_mgmt.contains("SAE") {
+                        Some("wpa3")
+                    } else if key_mgmt.contains("OWE") {
+                        Some("owe")
+                    } else if key_mgmt.contains("PSK") {
+                        Some(if protocol == "WPA" { "wpa" } else { "wpa2" })
+                    } else {
+                        None
+                    }
+                })
+                .collect(),
+            _ => vec![],
+        };
+        for name in found {
+            if !security.contains(&name) {
+                security.push(name);
+            }
         }
-    });
-    out.to_vec()
-}
-
-fn escape_invalid_unicode(bytestring: Vec<u8>) -> String {
-    let mut escaped = String::with_capacity(bytestring.len() * 2);
-    let mut bytes: &[u8] = &bytestring;
-    loop {
-        let s = std::str::from_utf8(bytes);
-        match s {
-            Err(e) => {
-                error!("Decoding {:?} failed: {}", bytes, e.to_string());
-                let (good, bad) = bytes.split_at(e.valid_up_to());
-                if !good.is_empty() {
-                    escaped += std::str::from_utf8(good).unwrap(); // this cannot fail
+    }
+    if security.is_empty() {
+        security.push("open");
+    }
+    (security, wps)
+}
+
+// Channel number and band in GHz of the frequency in MHz.
+fn channel(frequency: u32) -> Option<(u32, &'static str)> {
+    match frequency {
+        2484 => Some((14, "2.4")),
+        2412..=2472 => Some(((frequency - 2407) / 5, "2.4")),
+        5935 => Some((2, "6")),
+        5955..=7115 => Some(((frequency - 5950) / 5, "6")),
+        // 4.9 GHz public safety band, channels 182 to 196
+        4910..=4980 => Some(((frequency - 4000) / 5, "5")),
+        5000..=5895 => Some(((frequency - 5000) / 5, "5")),
+        58320..=70200 => Some(((frequency - 56160) / 2160, "60")),
+        _ => None,
+    }
+}
+
+// Maps dBm in [-100, -40] to 0..100 like NetworkManager's strength.
+fn quality(signal: i32) -> u32 {
+    ((signal.clamp(-100, -40) + 100) * 100 / 60) as u32
+}
+
+// Names as in the "sec" field, the index is the bit in the security mask
+// of the scan parameters.
+const SECURITY_NAMES: [&str; 7] = ["open", "wep", "wpa", "wpa2", "wpa3", "owe", "eap"];
+// Bands as in the "band" field, the index is the bit in the band mask of
+// the scan parameters.
+const BAND_NAMES: [&str; 4] = ["2.4", "5", "6", "60"];
+
+// types of the scan parameters
+const PARAM_SSID_PREFIX: u8 = 1;
+const PARAM_MIN_RSSI: u8 = 2;
+const PARAM_BANDS: u8 = 3;
+const PARAM_SECURITY: u8 = 4;
+const PARAM_MAX_RESULTS: u8 = 5;
+const PARAM_MAX_AGE: u8 = 6;
+pub(crate) const PARAMS_MAX_LENGTH: usize = 64;
+
+// types of the fields of a TLV encoded scan result entry
+const TLV_ENTRY: u8 = 1;
+const TLV_SSID: u8 = 1;
+const TLV_BSSID: u8 = 2;
+const TLV_RSSI: u8 = 3;
+const TLV_FREQUENCY: u8 = 4;
+const TLV_CHANNEL: u8 = 5;
+const TLV_QUALITY: u8 = 6;
+const TLV_SECURITY: u8 = 7;
+const TLV_FLAGS: u8 = 8;
+const TLV_COUNT: u8 = 9;
+const TLV_AGE: u8 = 10;
+// bits of the TLV flags field
+const TLV_FLAG_WPS: u8 = 0x1;
+const TLV_FLAG_KNOWN: u8 = 0x2;
+const TLV_FLAG_CONNECTED: u8 = 0x4;
+
+/// Encoding of the scan results.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ResultFormat {
+    Json = 0u8,
+    Tlv = 1u8,
+}
+
+impl std::convert::TryFrom<u8> for ResultFormat {
+    type Error = String;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        let result = match value {
+            0u8 => ResultFormat::Json,
+            1u8 => ResultFormat::Tlv,
+            _ => Err(format!("invalid scan result format: {}", value))?,
+        };
+
+        Ok(result)
+    }
+}
+
+/// State of the device the scan results are put into relation to.
+#[derive(Clone, Debug, Default)]
+pub struct ScanContext {
+    // time since the scan, if the results were taken from the cache
+    pub age: Option<Duration>,
+    // SSIDs of the networks the backend has credentials for
+    pub known: Vec<Vec<u8>>,
+    // SSID of the network currently connected to
+    pub connected: Option<Vec<u8>>,
+}
+
+/// Options applied to the scan results before they are passed to the client.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScanFilter {
+    // only networks whose SSID starts with these bytes
+    pub ssid_prefix: Vec<u8>,
+    // minimum signal level in dBm
+    pub min_signal: Option<i32>,
+    // mask of the accepted bands, see BAND_NAMES
+    pub bands: Option<u8>,
+    // mask of the accepted security, see SECURITY_NAMES
+    pub security: Option<u8>,
+    // maximum number of networks, the strongest are kept
+    pub max_results: Option<usize>,
+    // maximum age of cached results, 0 to always scan
+    pub max_age: Option<Duration>,
+}
+
+impl ScanFilter {
+    // Parses the scan parameters written by the client, a sequence of
+    // type (u8), length (u8) and value, see README.
+    pub fn parse(params: &[u8]) -> Result<ScanFilter, String> {
+        let mut filter = ScanFilter::default();
+        let mut rest = params;
+        while !rest.is_empty() {
+            let (kind, len) = match rest {
+                [kind, len, ..] if rest.len() >= 2 + *len as usize => (*kind, *len as usize),
+                _ => return Err(format!("truncated scan parameter {:x?}", rest)),
+            };
+            let value = &rest[2..2 + len];
+            match (kind, value) {
+                (PARAM_SSID_PREFIX, _) => filter.ssid_prefix = value.to_vec(),
+                (PARAM_MIN_RSSI, [rssi]) => filter.min_signal = Some(*rssi as i8 as i32),
+                (PARAM_BANDS, [bands]) => filter.bands = Some(*bands),
+                (PARAM_SECURITY, [security]) => filter.security = Some(*security),
+                (PARAM_MAX_RESULTS, [max_results]) => {
+                    filter.max_results = Some(*max_results as usize)
                 }
-                write!(&mut escaped, "{:#04X}", bad[0]).unwrap();
-                bytes = &bytes[(e.valid_up_to() + 1)..]; // skip the offending byte
-            }
-            Ok(s) => {
-                escaped += s;
-                return escaped;
+                (PARAM_MAX_AGE, [low, high]) => {
+                    filter.max_age =
+                        Some(Duration::from_secs(u16::from_le_bytes([*low, *high]) as u64))
+                }
+                _ => return Err(format!("invalid scan parameter {:x?}", &rest[..2 + len])),
             }
+            rest = &rest[2 + len..];
         }
+        Ok(filter)
+    }
+
+    // Applies the limit configured on the server, the lower one wins.
+    pub fn limit(mut self, max_results: Option<usize>) -> ScanFilter {
+        self.max_results = match (self.max_results, max_results) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self
+    }
+
+    fn matches(&self, ap: &AccessPoint) -> bool {
+        let in_mask = |mask: Option<u8>, names: &[&str], name: &str| {
+            mask.is_none_or(|mask| {
+                names
+                    .iter()
+                    .position(|n| *n == name)
+                    .is_some_and(|index| mask & (1 << index) != 0)
+            })
+        };
+        // networks of an unknown band, e.g. with iwd, aren't filtered by band
+        let band = channel(ap.frequency).map(|(_, band)| band);
+        let (security, _) = parse_security(&ap.flags);
+        ap.ssid.starts_with(&self.ssid_prefix)
+            && self.min_signal.is_none_or(|min| ap.signal >= min)
+            && band.is_none_or(|band| in_mask(self.bands, &BAND_NAMES, band))
+            && security
+                .iter()
+                .any(|name| in_mask(self.security, &SECURITY_NAMES, name))
     }
 }
 
-fn escape_json(bytestring: Vec<u8>) -> String {
-    let mut escaped = String::with_capacity(bytestring.len() * 2);
-    let unescaped_str = escape_invalid_unicode(bytestring);
-    for c in unescaped_str.chars() {
-        match c {
-            '"' => escaped += "\\\"",
-            '\\' => escaped += "\\\\",
-            '\x08' => escaped += "\\b",
-            '\n' => escaped += "\\n",
-            '\r' => escaped += "\\r",
-            '\x0C' => escaped += "\\f",
-            '\t' => escaped += "\\t",
-            c if c < ' ' => write!(&mut escaped, "\\u00{:02X}", c as u16).unwrap(),
-            c if c >= '\u{10000}' => {
-                let u = c as u32 - 0x10000u32;
-                let h = (u >> 10) as u16 + 0xD800u16;
-                let l = (u & 0x03FFu32) as u16 + 0xDC00u16;
-                write!(&mut escaped, "\\u{:04X}\\u{:04X}", h, l).unwrap()
+// Hidden networks are reported with an empty or zeroed SSID.
+fn is_hidden(ssid: &[u8]) -> bool {
+    ssid.iter().all(|b| *b == 0)
+}
+
+// Groups the access points by SSID, keeping the strongest one of each
+// network together with the number of access points, strongest first.
+fn group_aps(aps: Vec<AccessPoint>) -> Vec<(AccessPoint, usize)> {
+    let mut groups: Vec<(AccessPoint, usize)> = vec![];
+    for ap in aps.into_iter().filter(|ap| !is_hidden(&ap.ssid)) {
+        match groups.iter_mut().find(|(other, _)| other.ssid == ap.ssid) {
+            Some((strongest, count)) => {
+                *count += 1;
+                if ap.signal > strongest.signal {
+                    *strongest = ap;
+                }
             }
-            c if c > '~' => write!(&mut escaped, "\\u{:04X}", c as u16).unwrap(),
-            c => escaped.push(c),
+            None => groups.push((ap, 1)),
         }
     }
-    escaped
+    groups.sort_by(|(a, _), (b, _)| b.signal.cmp(&a.signal));
+    groups
+}
+
+fn filter_aps(aps: Vec<AccessPoint>, filter: &ScanFilter) -> Vec<(AccessPoint, usize)> {
+    let aps = aps.into_iter().filter(|ap| filter.matches(ap)).collect();
+    let mut groups = group_aps(aps);
+    if let Some(max_results) = filter.max_results {
+        groups.truncate(max_results);
+    }
+    groups
 }
 
-fn parse_aps(aps: &str) -> String {
-    let re = regex::Regex::new(r"(([0-9a-fA-F]{2}:){5}[0-9a-fA-F]{2})\t([0-9]+)\t(-?[0-9]+)\t((\[[a-zA-Z0-9+-]+\]))*\t([^\n]*)\n").unwrap();
-    let mut json: String = String::new();
-    json.push('[');
-    for cap in re.captures_iter(aps) {
-        if json.len() > 1 {
-            json.push(',');
+/// Entry of the JSON scan results, numbers are strings for compatibility
+/// with existing clients.
+#[derive(Debug, PartialEq, Serialize)]
+struct ScanEntry {
+    // SSID for display, invalid UTF-8 is replaced by U+FFFD
+    ssid: String,
+    // lossless SSID, only present if it is not valid UTF-8
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ssid_hex: Option<String>,
+    rssi: String,
+    mac: String,
+    // frequency, kept for existing clients
+    ch: String,
+    freq: String,
+    channel: String,
+    band: &'static str,
+    quality: String,
+    sec: Vec<&'static str>,
+    wps: String,
+    // credentials for the network are stored, so it can be connected without a password
+    known: String,
+    connected: String,
+    count: String,
+    // seconds since the networks were scanned, only present for cached results
+    #[serde(skip_serializing_if = "Option::is_none")]
+    age: Option<String>,
+}
+
+impl ScanEntry {
+    fn new(ap: &AccessPoint, count: usize, context: &ScanContext) -> ScanEntry {
+        let ssid_hex = std::str::from_utf8(&ap.ssid).is_err().then(|| {
+            ap.ssid
+                .iter()
+                .fold(String::new(), |hex, byte| hex + &format!("{:02x}", byte))
+        });
+        let (sec, wps) = parse_security(&ap.flags);
+        let (channel, band) = match channel(ap.frequency) {
+            Some((channel, band)) => (channel.to_string(), band),
+            None => (String::new(), ""),
+        };
+        ScanEntry {
+            ssid: String::from_utf8_lossy(&ap.ssid).to_string(),
+            ssid_hex,
+            rssi: ap.signal.to_string(),
+            mac: ap.bssid.clone(),
+            ch: ap.frequency.to_string(),
+            freq: ap.frequency.to_string(),
+            channel,
+            band,
+            quality: quality(ap.signal).to_string(),
+            sec,
+            wps: (wps as u8).to_string(),
+            known: (context.known.contains(&ap.ssid) as u8).to_string(),
+            connected: ((context.connected.as_ref() == Some(&ap.ssid)) as u8).to_string(),
+            count: count.to_string(),
+            age: context.age.map(|age| age.as_secs().to_string()),
         }
-        write!(
-            &mut json,
-            "{{\"ssid\":\"{}\",\
-               \"rssi\":\"{}\",\
-               \"mac\":\"{}\",\
-               \"ch\":\"{}\"}}",
-            escape_json(unescape_hex(&cap[7])),
-            &cap[4],
-            &cap[1],
-            &cap[3]
-        )
-        .unwrap();
-    }
-    json.push(']');
-    json
-}
-
-pub async fn scan(interface: String) -> Result<Vec<u8>, String> {
-    let scan_task = tokio::task::spawn_blocking(move || {
-        info!("Starting SSID scan");
-        let mut wpa = wpactrl::Client::builder()
-            .ctrl_path(format!("/var/run/wpa_supplicant/{}", interface))
-            .open()
-            .map_err(|e| e.to_string())?;
-        let output = wpa.request("SCAN").map_err(|e| e.to_string())?;
-        if output.trim() == "FAIL" {
-            return Err("SCAN failed.".to_string());
+    }
+}
+
+fn to_json(aps: &[(AccessPoint, usize)], context: &ScanContext) -> Result<String, String> {
+    let entries: Vec<ScanEntry> = aps
+        .iter()
+        .map(|(ap, count)| ScanEntry::new(ap, *count, context))
+        .collect();
+    serde_json::to_string(&entries).map_err(|e| e.to_string())
+}
+
+fn push_tlv(out: &mut Vec<u8>, kind: u8, value: &[u8]) {
+    out.push(kind);
+    out.push(value.len() as u8);
+    out.extend_from_slice(value);
+}
+
+fn mask(names: &[&str], all: &[&str]) -> u8 {
+    names
+        .iter()
+        .filter_map(|name| all.iter().position(|n| n == name))
+        .fold(0, |mask, index| mask | (1 << index))
+}
+
+// Binary alternative to the JSON, which repeats the key names in every
+// entry. Each entry is a TLV of type 1 containing the TLV fields of the
+// access point, unknown fields are left out, see README.
+fn encode_tlv(aps: &[(AccessPoint, usize)], context: &ScanContext) -> Vec<u8> {
+    let mut out = vec![];
+    for (ap, count) in aps {
+        let mut entry = vec![];
+        // the SSID is at most 32 bytes, so the entry always fits
+        push_tlv(&mut entry, TLV_SSID, &ap.ssid);
+        let bssid: Result<Vec<u8>, _> = ap
+            .bssid
+            .split(':')
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect();
+        match bssid {
+            Ok(bssid) if bssid.len() == 6 => push_tlv(&mut entry, TLV_BSSID, &bssid),
+            _ => debug!("Invalid BSSID {}", ap.bssid),
         }
-        std::thread::sleep(std::time::Duration::from_secs(3));
-        let output = wpa.request("SCAN_RESULTS").map_err(|e| e.to_string())?;
-        if output.trim() == "FAIL" {
-            return Err("SCAN_RESULTS failed.".to_string());
+        push_tlv(
+            &mut entry,
+            TLV_RSSI,
+            &[ap.signal.clamp(i8::MIN as i32, 0) as i8 as u8],
+        );
+        push_tlv(
+            &mut entry,
+            TLV_FREQUENCY,
+            &(ap.frequency.min(u16::MAX as u32) as u16).to_le_bytes(),
+        );
+        if let Some((channel, _)) = channel(ap.frequency) {
+            push_tlv(&mut entry, TLV_CHANNEL, &[channel as u8]);
         }
-        info!("Finished SSID scan");
-        Ok(output)
-    });
-    let scan_task_result = scan_task.await.map_err(|e| e.to_string())?;
-    match scan_task_result {
-        Ok(found_hotspots) => {
-            let json = parse_aps(&found_hotspots);
-            debug!("Scan successful: {:?}", json);
-            Ok(json.as_bytes().to_vec())
+        push_tlv(&mut entry, TLV_QUALITY, &[quality(ap.signal) as u8]);
+        let (security, wps) = parse_security(&ap.flags);
+        push_tlv(
+            &mut entry,
+            TLV_SECURITY,
+            &[mask(&security, &SECURITY_NAMES)],
+        );
+        let mut flags = if wps { TLV_FLAG_WPS } else { 0 };
+        if context.known.contains(&ap.ssid) {
+            flags |= TLV_FLAG_KNOWN;
+        }
+        if context.connected.as_ref() == Some(&ap.ssid) {
+            flags |= TLV_FLAG_CONNECTED;
         }
-        Err(e) => Err(e),
+        push_tlv(&mut entry, TLV_FLAGS, &[flags]);
+        push_tlv(&mut entry, TLV_COUNT, &[(*count).min(255) as u8]);
+        if let Some(age) = context.age {
+            push_tlv(
+                &mut entry,
+                TLV_AGE,
+                &(age.as_secs().min(u16::MAX as u64) as u16).to_le_bytes(),
+            );
+        }
+        push_tlv(&mut out, TLV_ENTRY, &entry);
     }
+    out
+}
+
+fn encode(
+    aps: &[(AccessPoint, usize)],
+    context: &ScanContext,
+    format: ResultFormat,
+) -> Result<Vec<u8>, String> {
+    match format {
+        ResultFormat::Json => to_json(aps, context).map(|json| json.into_bytes()),
+        ResultFormat::Tlv => Ok(encode_tlv(aps, context)),
+    }
+}
+
+// Encodes the results, dropping the weakest networks if they exceed max_length.
+fn encode_truncated(
+    aps: &[(AccessPoint, usize)],
+    context: &ScanContext,
+    format: ResultFormat,
+    max_length: usize,
+) -> Result<Vec<u8>, String> {
+    let encoded = encode(aps, context, format)?;
+    if encoded.len() <= max_length {
+        return Ok(encoded);
+    }
+    warn!(
+        "Scan results exceed {} bytes, dropping the weakest networks",
+        max_length
+    );
+    // the length grows with the number of networks, find the most that fit
+    let (mut fits, mut exceeds) = (0, aps.len());
+    while exceeds - fits > 1 {
+        let count = (fits + exceeds) / 2;
+        if encode(&aps[..count], context, format)?.len() <= max_length {
+            fits = count;
+        } else {
+            exceeds = count;
+        }
+    }
+    encode(&aps[..fits], context, format)
+}
+
+// Encodes the scanned access points for the client.
+pub fn results(
+    found_hotspots: Vec<AccessPoint>,
+    context: &ScanContext,
+    filter: &ScanFilter,
+    format: ResultFormat,
+    max_length: usize,
+) -> Result<Vec<u8>, String> {
+    let aps = filter_aps(found_hotspots, filter);
+    let results = encode_truncated(&aps, context, format, max_length)?;
+    debug!("Scan results: {:x?}", results);
+    Ok(results)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::backend::wpa_supplicant::parse_scan_results;
 
     #[test]
-    fn test_escape_invalid_unicode() {
-        let v1: Vec<u8> = vec![
-            0xF0u8, 0x00u8, 0x08u8, 0x01u8, 0x5Cu8, 0x02u8, 0x0Cu8, 0x03u8, 0xFFu8, 0x0Au8, 0xf0u8,
-            0x9fu8, 0x92u8, 0xa9u8, 0x0Du8, 0xF1u8, 0x22u8, 0x09u8,
+    fn test_parse_security() {
+        assert_eq!(parse_security("[ESS]"), (vec!["open"], false));
+        assert_eq!(parse_security("[WEP][ESS]"), (vec!["wep"], false));
+        assert_eq!(
+            parse_security("[WPA2-PSK+SAE-CCMP][SAE-H2E][ESS]"),
+            (vec!["wpa2", "wpa3"], false)
+        );
+        assert_eq!(
+            parse_security("[WPA2-EAP+FT/EAP-CCMP][WPS][ESS]"),
+            (vec!["eap"], true)
+        );
+        assert_eq!(parse_security("[RSN-OWE-CCMP][ESS]"), (vec!["owe"], false));
+    }
+
+    #[test]
+    fn test_channel() {
+        assert_eq!(channel(2412), Some((1, "2.4")));
+        assert_eq!(channel(2484), Some((14, "2.4")));
+        assert_eq!(channel(5180), Some((36, "5")));
+        assert_eq!(channel(4920), Some((184, "5")));
+        assert_eq!(channel(4990), None);
+        assert_eq!(channel(5955), Some((1, "6")));
+        assert_eq!(channel(60480), Some((2, "60")));
+        assert_eq!(channel(1234), None);
+        assert_eq!(quality(-40), 100);
+        assert_eq!(quality(-70), 50);
+        assert_eq!(quality(-110), 0);
+    }
+
+    fn access_point(ssid: &str, bssid: &str, signal: i32) -> AccessPoint {
+        AccessPoint {
+            bssid: bssid.to_string(),
+            frequency: 2412,
+            signal,
+            flags: "[ESS]".to_string(),
+            ssid: ssid.as_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn test_filter_aps() {
+        let aps = vec![
+            access_point("mesh", "01:00:00:00:00:01", -70),
+            access_point("other", "02:00:00:00:00:01", -60),
+            access_point("mesh", "01:00:00:00:00:02", -50),
+            access_point("", "03:00:00:00:00:01", -40),
+            access_point("\0\0", "04:00:00:00:00:01", -40),
+            access_point("mesh", "01:00:00:00:00:03", -80),
         ];
-        let unescaped =
-            unescape_hex(r"\xF0\x00\x08\x01\\\x02\x0C\x03\xFF\x0A\xf0\x9f\x92\xa9\x0D\xF1\x22\x09");
-        assert_eq!(unescaped, v1);
+        let groups = filter_aps(aps.clone(), &ScanFilter::default());
+        let groups: Vec<(&str, usize)> = groups
+            .iter()
+            .map(|(ap, count)| (ap.bssid.as_str(), *count))
+            .collect();
         assert_eq!(
-            escape_invalid_unicode(v1.clone()),
-            "0xF0\x00\x08\x01\x5C\x02\x0C\x030xFF\x0A💩\x0D0xF1\x22\x09"
+            groups,
+            vec![("01:00:00:00:00:02", 3), ("02:00:00:00:00:01", 1)]
+        );
+        let filter = ScanFilter {
+            max_results: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(filter_aps(aps.clone(), &filter).len(), 1);
+        let filter = ScanFilter {
+            ssid_prefix: b"oth".to_vec(),
+            ..Default::default()
+        };
+        assert_eq!(filter_aps(aps.clone(), &filter)[0].0.ssid, b"other");
+        let filter = ScanFilter {
+            min_signal: Some(-65),
+            ..Default::default()
+        };
+        assert_eq!(filter_aps(aps.clone(), &filter)[0].1, 1);
+        let filter = ScanFilter {
+            bands: Some(0b10),
+            ..Default::default()
+        };
+        assert!(filter_aps(aps.clone(), &filter).is_empty());
+        let mut unknown = access_point("unknown", "05:00:00:00:00:01", -40);
+        unknown.frequency = 0;
+        assert_eq!(filter_aps(vec![unknown], &filter).len(), 1);
+        let filter = ScanFilter {
+            security: Some(0b1),
+            ..Default::default()
+        };
+        assert_eq!(filter_aps(aps, &filter).len(), 2);
+    }
+
+    #[test]
+    fn test_parse_filter() {
+        assert_eq!(ScanFilter::parse(&[]).unwrap(), ScanFilter::default());
+        assert_eq!(
+            ScanFilter::parse(&[1, 2, b'a', b'b', 2, 1, 0xb5, 3, 1, 0x3, 4, 1, 0x18, 5, 1, 10])
+                .unwrap(),
+            ScanFilter {
+                ssid_prefix: b"ab".to_vec(),
+                min_signal: Some(-75),
+                bands: Some(0x3),
+                security: Some(0x18),
+                max_results: Some(10),
+                max_age: None,
+            }
         );
+        assert!(ScanFilter::parse(&[1, 3, b'a']).is_err());
+        assert!(ScanFilter::parse(&[2, 2, 0, 0]).is_err());
+        assert!(ScanFilter::parse(&[9, 0]).is_err());
         assert_eq!(
-            escape_json(v1),
-            "0xF0\\u0000\\b\\u0001\\\\\\u0002\\f\\u00030xFF\\n\\uD83D\\uDCA9\\r0xF1\\\"\\t"
-        )
+            ScanFilter::parse(&[6, 2, 0x2c, 0x01]).unwrap().max_age,
+            Some(Duration::from_secs(300))
+        );
+        let filter = ScanFilter::parse(&[5, 1, 10]).unwrap();
+        assert_eq!(filter.clone().limit(Some(5)).max_results, Some(5));
+        assert_eq!(filter.limit(None).max_results, Some(10));
     }
+
+    #[test]
+    fn test_encode_tlv() {
+        let mut ap = access_point("ab", "01:02:03:04:05:06", -50);
+        ap.flags = "[WPA2-PSK-CCMP][WPS][ESS]".to_string();
+        assert_eq!(
+            encode_tlv(&[(ap.clone(), 3)], &ScanContext::default()),
+            vec![
+                1, 34, 1, 2, b'a', b'b', 2, 6, 1, 2, 3, 4, 5, 6, 3, 1, 0xce, 4, 2, 0x6c, 0x09, 5,
+                1, 1, 6, 1, 83, 7, 1, 0x8, 8, 1, 0x1, 9, 1, 3
+            ]
+        );
+        ap.bssid = "invalid".to_string();
+        ap.frequency = 1234;
+        assert_eq!(
+            encode_tlv(&[(ap.clone(), 1)], &ScanContext::default())[1],
+            34 - 8 - 3
+        );
+        let context = ScanContext {
+            age: Some(Duration::from_secs(70000)),
+            known: vec![b"ab".to_vec()],
+            connected: Some(b"ab".to_vec()),
+        };
+        assert_eq!(
+            encode_tlv(&[(ap, 1)], &context)[19..],
+            [8, 1, 0x7, 9, 1, 1, 10, 2, 0xff, 0xff]
+        );
+    }
+
+    #[test]
+    fn test_scan_entry() {
+        let entry = ScanEntry::new(
+            &access_point("0xF0", "01:02:03:04:05:06", -50),
+            1,
+            &ScanContext::default(),
+        );
+        assert_eq!(entry.ssid, "0xF0");
+        assert_eq!(entry.ssid_hex, None);
+        let mut ap = access_point("", "01:02:03:04:05:06", -50);
+        ap.ssid = vec![0xf0, b'a', b'"'];
+        let context = ScanContext {
+            age: Some(Duration::from_secs(12)),
+            known: vec![b"other".to_vec(), ap.ssid.clone()],
+            connected: Some(b"other".to_vec()),
+        };
+        let entry = ScanEntry::new(&ap, 1, &context);
+        assert_eq!(entry.ssid, "\u{FFFD}a\"");
+        assert_eq!(entry.ssid_hex.as_deref(), Some("f06122"));
+        assert_eq!(entry.age.as_deref(), Some("12"));
+        assert_eq!((entry.known.as_str(), entry.connected.as_str()), ("1", "0"));
+        let json = to_json(&[(ap, 1)], &ScanContext::default()).unwrap();
+        assert!(json.starts_with("[{\"ssid\":\"\u{FFFD}a\\\"\",\"ssid_hex\":\"f06122\",\"rssi\""));
+    }
+
+    #[test]
+    fn test_encode_truncated() {
+        let aps: Vec<(AccessPoint, usize)> = (0..10)
+            .map(|i| (access_point(&format!("{}", i), "01:02:03:04:05:06", -50), 1))
+            .collect();
+        let tlv = encode_truncated(&aps, &ScanContext::default(), ResultFormat::Tlv, 1000).unwrap();
+        assert_eq!(tlv.len(), 10 * 35);
+        let tlv = encode_truncated(&aps, &ScanContext::default(), ResultFormat::Tlv, 100).unwrap();
+        assert_eq!(tlv.len(), 2 * 35);
+        let json =
+            encode_truncated(&aps, &ScanContext::default(), ResultFormat::Json, 1000).unwrap();
+        let json: Vec<serde_json::Value> = serde_json::from_slice(&json).unwrap();
+        assert_eq!(json.len(), 5);
+    }
+
     #[test]
     fn test_parse() {
         let input = r#"01:02:03:04:05:06	1234	-99	[WPA-PSK-CCMP+TKIP][WPA2-PSK-CCMP+TKIP][WPS][ESS]	SomeName\xf0\x9f\x92\xa9
@@ -146,10 +634,14 @@ mod tests {
         03:04:05:06:07:08	3456	-97	[WPA2-PSK-CCMP][WPS][ESS]	"SomeOtherName"
         04:05:06:07:08:09	4567	-96	[WPA2-PSK-CCMP][ESS]	
         "#;
-        let output = parse_aps(input);
+        let aps: Vec<(AccessPoint, usize)> = parse_scan_results(input)
+            .into_iter()
+            .map(|ap| (ap, 1))
+            .collect();
+        let output = to_json(&aps, &ScanContext::default()).unwrap();
         assert_eq!(
             output,
-            r#"[{"ssid":"SomeName\uD83D\uDCA9","rssi":"-99","mac":"01:02:03:04:05:06","ch":"1234"},{"ssid":"\u0000\u0000\\\u0000\\\u0001\u0001\u0001","rssi":"-98","mac":"02:03:04:05:06:07","ch":"2345"},{"ssid":"\"SomeOtherName\"","rssi":"-97","mac":"03:04:05:06:07:08","ch":"3456"},{"ssid":"","rssi":"-96","mac":"04:05:06:07:08:09","ch":"4567"}]"#
+            r#"[{"ssid":"SomeName💩","rssi":"-99","mac":"01:02:03:04:05:06","ch":"1234","freq":"1234","channel":"","band":"","quality":"1","sec":["wpa","wpa2"],"wps":"1","known":"0","connected":"0","count":"1"},{"ssid":"\u0000\u0000\\\u0000\\\u0001\u0001\u0001","rssi":"-98","mac":"02:03:04:05:06:07","ch":"2345","freq":"2345","channel":"","band":"","quality":"3","sec":["wpa","wpa2"],"wps":"0","known":"0","connected":"0","count":"1"},{"ssid":"\"SomeOtherName\"","rssi":"-97","mac":"03:04:05:06:07:08","ch":"3456","freq":"3456","channel":"","band":"","quality":"5","sec":["wpa2"],"wps":"1","known":"0","connected":"0","count":"1"},{"ssid":"","rssi":"-96","mac":"04:05:06:07:08:09","ch":"4567","freq":"4567","channel":"","band":"","quality":"6","sec":["wpa2"],"wps":"0","known":"0","connected":"0","count":"1"}]"#
         );
     }
 }